@@ -0,0 +1,416 @@
+use std::{fmt, io, path::Path};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Sample rate tracks are decoded at for analysis. Plenty for loudness.
+const SAMPLE_RATE: usize = 8000;
+
+/// How long each loudness measurement is, in seconds.
+const WINDOW: f32 = 0.25;
+
+/// How far below the loud parts of a track something is before it counts as
+/// quiet, in dB.
+const QUIET_DB: f32 = 20.0;
+
+/// Anything below this is silence, however quiet the track is. In dBFS.
+const SILENCE_DB: f32 = -50.0;
+
+/// How far below the loud parts the end of a track can be before it counts
+/// as fading out, in dB.
+const FADE_DB: f32 = 6.0;
+
+/// Good regions shorter than this are dropped, in seconds.
+const MIN_REGION: f32 = 2.0;
+
+/// How many samples go into each step of the onset envelope.
+const HOP: usize = 256;
+
+/// The range of tempos we look for beats at, in beats per minute.
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 180.0;
+
+/// How close to the best tempo a faster one has to line up to be picked
+/// instead, from 0 to 1.
+const PERIOD_TOLERANCE: f32 = 0.9;
+
+/// Beats with onsets weaker than this much of a typical beat's are dropped.
+const WEAK_BEAT: f32 = 0.2;
+
+/// How many onset steps a beat can drift from where the tempo says it should
+/// be, at least.
+const BEAT_DRIFT: usize = 2;
+
+/// Bumped whenever the analysis changes, so tracks analysed by an older
+/// version get analysed again.
+pub const ANALYSIS_VERSION: u32 = 3;
+
+/// EBU R128 loudness normalisation, the same target for every soundtrack.
+pub const LOUDNORM: &str = "loudnorm=I=-16:TP=-1.5:LRA=11";
+
+/// What we learnt from listening to a track.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Analysis {
+    /// The [`ANALYSIS_VERSION`] this was made with.
+    #[serde(default)]
+    pub version: u32,
+    /// The parts of the track that aren't silent or fading out, as
+    /// (start, end) in seconds.
+    pub regions: Vec<(f32, f32)>,
+    /// When each beat is, in seconds. Empty if we couldn't find any.
+    #[serde(default)]
+    pub beats: Vec<f32>,
+    /// What the first pass of [`LOUDNORM`] measured for the whole track.
+    #[serde(default)]
+    pub loudness: Option<Loudness>,
+}
+
+/// The measurements [`LOUDNORM`] needs to normalise a track in a single,
+/// linear pass.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Loudness {
+    pub input_i: f32,
+    pub input_tp: f32,
+    pub input_lra: f32,
+    pub input_thresh: f32,
+    pub target_offset: f32,
+}
+
+impl Loudness {
+    /// The loudnorm filter that applies these measurements.
+    pub fn filter(&self) -> String {
+        format!(
+            "{LOUDNORM}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+            self.input_i, self.input_tp, self.input_lra, self.input_thresh, self.target_offset
+        )
+    }
+}
+
+/// Why a track couldn't be analysed.
+#[derive(Debug)]
+pub enum AnalysisError {
+    /// ffmpeg couldn't be run, trying again later might work.
+    Io(io::Error),
+    /// ffmpeg ran but couldn't make sense of the track.
+    Ffmpeg(String),
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisError::Io(e) => write!(f, "Failed to run ffmpeg: {}", e),
+            AnalysisError::Ffmpeg(e) => write!(f, "Ffmpeg Error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for AnalysisError {
+    fn from(e: io::Error) -> Self {
+        AnalysisError::Io(e)
+    }
+}
+
+/// How loudnorm prints its measurements, every value is a string.
+#[derive(Deserialize)]
+struct LoudnormOutput {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+/// Decode a track with ffmpeg and work out which parts of it are good to
+/// clip.
+pub async fn analyse(path: &Path) -> Result<Analysis, AnalysisError> {
+    let samples = decode(path).await?;
+    Ok(Analysis {
+        version: ANALYSIS_VERSION,
+        regions: good_regions(&loudness(&samples)),
+        beats: beats(&onset_envelope(&samples)),
+        loudness: measure_loudness(path).await?,
+    })
+}
+
+/// Run the first pass of [`LOUDNORM`] over a whole track. `None` if it
+/// couldn't be measured, errors if ffmpeg couldn't be run.
+async fn measure_loudness(path: &Path) -> io::Result<Option<Loudness>> {
+    // loudnorm prints what it measured at the info log level.
+    let output = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(path)
+        .arg("-af")
+        .arg(format!("{LOUDNORM}:print_format=json"))
+        .args(["-f", "null", "-"])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        warn!("Failed to measure '{}': {}", path.display(), stderr.trim());
+        return Ok(None);
+    }
+    Ok(parse_loudness(path, &stderr))
+}
+
+/// Read what loudnorm printed.
+fn parse_loudness(path: &Path, stderr: &str) -> Option<Loudness> {
+    // The measurements are the last thing printed.
+    let json = stderr.get(stderr.rfind('{')?..=stderr.rfind('}')?)?;
+    let measured: LoudnormOutput = match serde_json::from_str(json) {
+        Ok(measured) => measured,
+        Err(why) => {
+            warn!(
+                "Unexpected loudnorm output for '{}': {}",
+                path.display(),
+                why
+            );
+            return None;
+        }
+    };
+
+    // Silent tracks measure as -inf, those can't be normalised.
+    let value = |text: &str| text.parse::<f32>().ok().filter(|value| value.is_finite());
+    Some(Loudness {
+        input_i: value(&measured.input_i)?,
+        input_tp: value(&measured.input_tp)?,
+        input_lra: value(&measured.input_lra)?,
+        input_thresh: value(&measured.input_thresh)?,
+        target_offset: value(&measured.target_offset)?,
+    })
+}
+
+/// Decode a track to mono samples at [`SAMPLE_RATE`].
+async fn decode(path: &Path) -> Result<Vec<f32>, AnalysisError> {
+    let output = tokio::process::Command::new("ffmpeg")
+        .args(["-v", "error", "-i"])
+        .arg(path)
+        .args(["-ac", "1", "-ar"])
+        .arg(SAMPLE_RATE.to_string())
+        .args(["-f", "f32le", "-"])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AnalysisError::Ffmpeg(stderr.trim().to_string()));
+    }
+    Ok(output
+        .stdout
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect())
+}
+
+/// The loudness of each [`WINDOW`] of samples, in dBFS.
+fn loudness(samples: &[f32]) -> Vec<f32> {
+    let window = (SAMPLE_RATE as f32 * WINDOW) as usize;
+    samples
+        .chunks(window)
+        .map(|chunk| {
+            let mean_square =
+                chunk.iter().map(|sample| sample * sample).sum::<f32>() / chunk.len() as f32;
+            10.0 * mean_square.max(1e-10).log10()
+        })
+        .collect()
+}
+
+/// Find the runs of windows that are loud enough, leaving out the fade at the
+/// end of the track.
+fn good_regions(loudness: &[f32]) -> Vec<(f32, f32)> {
+    // How loud the loud parts are. Not the loudest, so a few peaks don't
+    // make everything else look quiet.
+    let mut sorted = loudness.to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let Some(&reference) = sorted.get(sorted.len() * 9 / 10) else {
+        return vec![];
+    };
+    let threshold = (reference - QUIET_DB).max(SILENCE_DB);
+
+    let mut good: Vec<bool> = loudness.iter().map(|db| *db >= threshold).collect();
+    for (is_good, db) in good.iter_mut().zip(loudness).rev() {
+        if *db >= reference - FADE_DB {
+            break;
+        }
+        *is_good = false;
+    }
+
+    let mut regions = vec![];
+    let mut start = None;
+    for (i, is_good) in good.iter().chain([&false]).enumerate() {
+        match (start, is_good) {
+            (None, true) => start = Some(i),
+            (Some(first), false) => {
+                let region = (first as f32 * WINDOW, i as f32 * WINDOW);
+                if region.1 - region.0 >= MIN_REGION {
+                    regions.push(region);
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    regions
+}
+
+/// How much the loudness goes up at each [`HOP`] of samples. Peaks are where
+/// notes and drums start.
+fn onset_envelope(samples: &[f32]) -> Vec<f32> {
+    let energy: Vec<f32> = samples
+        .chunks(HOP)
+        .map(|chunk| (chunk.iter().map(|sample| sample * sample).sum::<f32>() + 1e-10).ln())
+        .collect();
+    let mut onsets = vec![0.0];
+    onsets.extend(energy.windows(2).map(|pair| (pair[1] - pair[0]).max(0.0)));
+    onsets
+}
+
+/// Find the beats by working out the tempo the onsets repeat at, then
+/// following that grid through the track.
+fn beats(onsets: &[f32]) -> Vec<f32> {
+    let steps_per_sec = SAMPLE_RATE as f32 / HOP as f32;
+    let min_period = (steps_per_sec * 60.0 / MAX_BPM).round() as usize;
+    let max_period = (steps_per_sec * 60.0 / MIN_BPM).round() as usize;
+    if onsets.len() < max_period * 4 {
+        return vec![];
+    }
+
+    // Beats rarely land exactly on a step, so smear each onset over its
+    // neighbours before comparing them.
+    let smoothed: Vec<f32> = (0..onsets.len())
+        .map(|i| {
+            let before = i.checked_sub(1).map_or(0.0, |i| onsets[i]);
+            let after = onsets.get(i + 1).copied().unwrap_or(0.0);
+            onsets[i] + (before + after) / 2.0
+        })
+        .collect();
+
+    // The beat period is the delay the onsets line up with themselves best
+    // at. Multiples of it line up about as well, so take the shortest one
+    // that's nearly as good.
+    let correlation = |period: usize| {
+        let sum: f32 = smoothed
+            .iter()
+            .zip(&smoothed[period..])
+            .map(|(a, b)| a * b)
+            .sum();
+        sum / (onsets.len() - period) as f32
+    };
+    let correlations: Vec<f32> = (min_period..=max_period).map(correlation).collect();
+    let best = correlations.iter().copied().fold(0.0, f32::max);
+    if best <= 0.0 {
+        return vec![];
+    }
+    let Some(period) = correlations
+        .iter()
+        .position(|correlation| *correlation >= best * PERIOD_TOLERANCE)
+        .map(|index| min_period + index)
+    else {
+        return vec![];
+    };
+
+    // Start from the strongest onset, it's almost certainly on a beat. The
+    // tempo drifts a little, so from there follow the strongest onset near
+    // where each beat should be instead of sticking to a grid.
+    let Some(anchor) = (0..onsets.len()).max_by(|a, b| onsets[*a].total_cmp(&onsets[*b])) else {
+        return vec![];
+    };
+    let drift = BEAT_DRIFT.max(period / 4);
+    let strongest_near = |step: usize| {
+        (step.saturating_sub(drift)..(step + drift + 1).min(onsets.len()))
+            .max_by(|a, b| onsets[*a].total_cmp(&onsets[*b]))
+            .unwrap_or(step)
+    };
+
+    let mut steps = vec![anchor];
+    let mut step = anchor;
+    while let Some(next) = step.checked_sub(period) {
+        step = strongest_near(next);
+        steps.push(step);
+    }
+    steps.reverse();
+    step = anchor;
+    while step + period < onsets.len() {
+        step = strongest_near(step + period);
+        steps.push(step);
+    }
+
+    // Following the tempo through silence finds "beats" in the noise, leave
+    // out anything much weaker than a typical beat.
+    let mut strengths: Vec<f32> = steps.iter().map(|step| onsets[*step]).collect();
+    strengths.sort_by(f32::total_cmp);
+    let typical = strengths[strengths.len() / 2];
+
+    steps
+        .into_iter()
+        .filter(|step| onsets[*step] >= typical * WEAK_BEAT)
+        .map(|step| step as f32 / steps_per_sec)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `secs` of a sine wave at `amplitude`, at [`SAMPLE_RATE`].
+    fn tone(secs: f32, amplitude: impl Fn(f32) -> f32) -> Vec<f32> {
+        (0..(secs * SAMPLE_RATE as f32) as usize)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                amplitude(t / secs) * (t * 440.0 * std::f32::consts::TAU).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skips_silence_and_fades() {
+        let mut samples = tone(2.0, |_| 0.0);
+        samples.extend(tone(10.0, |_| 0.5));
+        samples.extend(tone(4.0, |progress| 0.5 * (1.0 - progress)));
+
+        let regions = good_regions(&loudness(&samples));
+        assert_eq!(regions.len(), 1, "{regions:?}");
+        let (start, end) = regions[0];
+        assert!((start - 2.0).abs() <= WINDOW, "{start}");
+        // The fade is cut once it's FADE_DB down, halfway through.
+        assert!((14.0 - WINDOW..=14.0 + WINDOW).contains(&end), "{end}");
+    }
+
+    #[test]
+    fn finds_beats_in_a_click_track() {
+        // A click every 16 steps, about 117 BPM, over a little noise.
+        let period = 16 * HOP;
+        let samples: Vec<f32> = (0..SAMPLE_RATE * 20)
+            .map(|i| match i % period < 64 {
+                true => 0.8,
+                false => 0.001 * ((i * 7919) % 13) as f32,
+            })
+            .collect();
+
+        let beats = beats(&onset_envelope(&samples));
+        let expected = period as f32 / SAMPLE_RATE as f32;
+        assert!(beats.len() >= 35, "{beats:?}");
+        for pair in beats.windows(2) {
+            assert!((pair[1] - pair[0] - expected).abs() < 0.01, "{beats:?}");
+        }
+        // On the clicks, not just the right distance apart.
+        for beat in &beats {
+            let offset = beat % expected;
+            assert!(offset < 0.05 || expected - offset < 0.05, "{beat}");
+        }
+    }
+
+    #[test]
+    fn silence_has_no_beats() {
+        assert!(beats(&onset_envelope(&tone(20.0, |_| 0.0))).is_empty());
+        assert!(beats(&[]).is_empty());
+    }
+
+    #[test]
+    fn quiet_tracks_have_no_regions() {
+        assert!(good_regions(&loudness(&tone(10.0, |_| 0.0))).is_empty());
+        assert!(good_regions(&[]).is_empty());
+    }
+}
@@ -1,62 +1,314 @@
-use std::sync::Arc;
-use std::{fmt, path::PathBuf};
+use std::fmt;
+use std::sync::{Arc, OnceLock};
 
-use log::{debug, trace, warn};
+use log::{trace, warn};
 use rand::{prelude::SmallRng, Rng};
 use regex::Regex;
 use reqwest::header::CONTENT_TYPE;
 use serde_json::Value;
-use serenity::prelude::*;
+use serenity::{builder::CreateEmbed, prelude::*, utils::colours};
 
+use crate::analysis::Analysis;
+use crate::library::{find_number, pick_weighted, random_pool, search, tag_pool, Search, Track};
+use crate::options::{parse_timestamp, RenderOptions};
+use crate::shuffle::ShuffleHistory;
 use crate::spotify::SpotifyError;
-use crate::{Music, MAX_AUDIO_LENGTH};
+use crate::{env_var, Music, CLIP_LENGTH};
 use crate::{error::MiitopiaError, spotify::Spotify};
 
 pub enum AudioSource {
     Miitopia,
-    Url(String),
-    Spotify(String),
+    /// A Miitopia track with a title like this.
+    Search(String),
+    /// A Miitopia track by its track number.
+    Number(u32),
+    /// A link to some audio, and where the link says to start.
+    Url(String, Option<f32>),
+    /// A spotify track id, and where the link says to start.
+    Spotify(String, Option<f32>),
 }
 
 impl fmt::Display for AudioSource {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AudioSource::Miitopia => write!(f, "Miitopia"),
-            AudioSource::Url(url) => write!(f, "Url:{}", url),
-            AudioSource::Spotify(id) => write!(f, "Spotify track:{}", id),
+            AudioSource::Search(query) => write!(f, "Miitopia search:{}", query),
+            AudioSource::Number(number) => write!(f, "Miitopia track:#{}", number),
+            AudioSource::Url(url, _) => write!(f, "Url:{}", url),
+            AudioSource::Spotify(id, _) => write!(f, "Spotify track:{}", id),
+        }
+    }
+}
+
+/// A piece of audio to put on a video.
+#[derive(Clone, Debug)]
+pub struct Clip {
+    /// The path or url of the audio, anything ffmpeg can read.
+    pub audio: String,
+    /// Where to start in the audio, in seconds.
+    pub start: f32,
+    /// How long the clip is, in seconds.
+    pub duration: f32,
+    pub source: ClipSource,
+    /// Tracks the message might have meant, when a random one was played
+    /// instead.
+    pub suggestions: Vec<Track>,
+}
+
+/// Where the audio in a [`Clip`] came from, for crediting it.
+#[derive(Clone, Debug)]
+pub enum ClipSource {
+    Miitopia(Track),
+    Url(String),
+    Spotify {
+        name: String,
+        artists: String,
+        url: String,
+    },
+}
+
+impl Clip {
+    fn new(audio: String, (start, duration): (f32, f32), source: ClipSource) -> Clip {
+        Clip {
+            audio,
+            start,
+            duration,
+            source,
+            suggestions: Vec::new(),
+        }
+    }
+
+    /// The name of the track, if it has a proper one.
+    pub fn title(&self) -> Option<String> {
+        match &self.source {
+            ClipSource::Miitopia(track) => Some(track.name()),
+            ClipSource::Url(_) => None,
+            ClipSource::Spotify { name, .. } => Some(name.clone()),
+        }
+    }
+
+    /// Describe the clip in an embed.
+    pub fn embed(&self, em: &mut CreateEmbed) {
+        em.color(colours::css::POSITIVE);
+        match &self.source {
+            ClipSource::Miitopia(track) => {
+                em.title(format!("🎵 {}", track.name()));
+                if let Some(number) = track.number {
+                    em.field("Track", format!("#{number}"), true);
+                }
+                if let Some(credits) = track.credits() {
+                    em.field("By", credits, true);
+                }
+            }
+            ClipSource::Url(url) => {
+                // Just the host, the full url is usually long and ugly.
+                let host = reqwest::Url::parse(url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_else(|| url.clone());
+                em.title(format!("🔗 {host}")).url(url);
+            }
+            ClipSource::Spotify { name, artists, url } => {
+                em.title(format!("🎧 {name}")).url(url).description(artists);
+            }
+        }
+        em.field(
+            "Clip",
+            format!(
+                "{} - {}",
+                timestamp(self.start),
+                timestamp(self.start + self.duration)
+            ),
+            true,
+        );
+        if !self.suggestions.is_empty() {
+            let names: Vec<String> = self
+                .suggestions
+                .iter()
+                .map(|track| match track.number {
+                    Some(number) => format!("`#{}` {}", number, track.name()),
+                    None => track.name(),
+                })
+                .collect();
+            em.field("Did you mean", names.join("\n"), false);
         }
     }
 }
 
+impl fmt::Display for Clip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            ClipSource::Miitopia(track) => write!(f, "{}", track)?,
+            ClipSource::Url(url) => write!(f, "{}", url)?,
+            ClipSource::Spotify { name, artists, .. } => write!(f, "{} by {}", name, artists)?,
+        }
+        write!(f, " @ {}", timestamp(self.start))
+    }
+}
+
+/// How far a clip's start or end can be moved to land on a beat, in seconds.
+const MAX_BEAT_SNAP: f32 = 0.5;
+
+/// How long spotify previews are, in seconds.
+const SPOTIFY_PREVIEW_LENGTH: f32 = 30.0;
+
+/// Format seconds like "1:23.4".
+pub fn timestamp(secs: f32) -> String {
+    let mins = (secs / 60.0).floor();
+    format!("{}:{:04.1}", mins, secs - mins * 60.0)
+}
+
+/// The longest clip someone can ask for. Set with the
+/// `MIITOPIA_MAX_CLIP_LENGTH` env var, in seconds.
+pub fn max_clip_length() -> f32 {
+    static MAX: OnceLock<f32> = OnceLock::new();
+    *MAX.get_or_init(|| {
+        env_var::<f32>("MIITOPIA_MAX_CLIP_LENGTH")
+            .filter(|secs| *secs >= CLIP_LENGTH)
+            .unwrap_or(30.0)
+    })
+}
+
+/// Work out which part of the audio to use, as (start, duration) in seconds.
+/// `total` is how long the audio is, if we know. Without a start in `options`
+/// one is picked at random, inside one of the good regions in `analysis` if it
+/// fits. Whatever was picked for them is snapped to the nearest beat. Errors
+/// with why the clip can't be cut.
+pub fn clip_range(
+    options: &RenderOptions,
+    total: Option<f32>,
+    analysis: &Analysis,
+    rng: &mut SmallRng,
+) -> Result<(f32, f32), String> {
+    let max = max_clip_length();
+    let mut duration = options.length.unwrap_or(CLIP_LENGTH);
+    if duration <= 0.0 || duration > max {
+        return Err(format!(
+            "Clips have to be longer than 0 and at most {max} seconds long."
+        ));
+    }
+
+    let Some(total) = total else {
+        return Ok((options.start.unwrap_or(0.0), duration));
+    };
+    let start = match options.start {
+        Some(start) => start,
+        None => {
+            let start = random_start(total, duration, &analysis.regions, rng);
+            nearest_beat(&analysis.beats, start)
+                .filter(|beat| beat + duration <= total)
+                .unwrap_or(start)
+        }
+    };
+    if options.length.is_none() {
+        if let Some(end) = nearest_beat(&analysis.beats, start + duration)
+            .filter(|end| *end > start && *end - start <= max && *end <= total)
+        {
+            duration = end - start;
+        }
+    }
+
+    if start + duration > total {
+        return Err(format!(
+            "{} - {} doesn't fit, the track is only {} long.",
+            timestamp(start),
+            timestamp(start + duration),
+            timestamp(total)
+        ));
+    }
+    Ok((start, duration))
+}
+
+/// The beat closest to `time`, if there's one close enough to snap to.
+fn nearest_beat(beats: &[f32], time: f32) -> Option<f32> {
+    let after = beats.partition_point(|beat| *beat < time);
+    let before = after.checked_sub(1).and_then(|index| beats.get(index));
+    [before, beats.get(after)]
+        .into_iter()
+        .flatten()
+        .copied()
+        .filter(|beat| (beat - time).abs() <= MAX_BEAT_SNAP)
+        .min_by(|a, b| (a - time).abs().total_cmp(&(b - time).abs()))
+}
+
+/// Pick a random start for a clip so it fits in one of `regions`, or anywhere
+/// in the track if none of them are long enough.
+fn random_start(total: f32, duration: f32, regions: &[(f32, f32)], rng: &mut SmallRng) -> f32 {
+    // The first and last start in each region the clip fits in.
+    let fits: Vec<(f32, f32)> = regions
+        .iter()
+        .map(|(start, end)| (*start, end.min(total) - duration))
+        .filter(|(first, last)| first <= last)
+        .collect();
+
+    if fits.is_empty() {
+        return match total > duration {
+            true => rng.gen_range(0.0..total - duration),
+            false => 0.0,
+        };
+    }
+
+    // Every start is as likely as any other, so longer regions get picked
+    // more often.
+    let spare: f32 = fits.iter().map(|(first, last)| last - first).sum();
+    let mut offset = rng.gen_range(0.0..=spare);
+    for (first, last) in &fits {
+        if offset <= last - first {
+            return first + offset;
+        }
+        offset -= last - first;
+    }
+    fits[0].0
+}
+
 impl AudioSource {
-    pub fn from_msg_content(msg_content: &String) -> AudioSource {
+    /// Work out where to get audio from. `query` is the message without any
+    /// options, see [`RenderOptions::from_msg_content`].
+    ///
+    /// [`RenderOptions::from_msg_content`]: crate::options::RenderOptions::from_msg_content
+    pub fn from_msg_content(msg_content: &str, query: &str) -> AudioSource {
         // Check for spotify matches.
-        let spotify_re = Regex::new(r"https://open.spotify.com/track/([a-zA-Z0-9]*)").unwrap();
+        let spotify_re =
+            Regex::new(r"https://open.spotify.com/track/([a-zA-Z0-9]*)[^\s]*").unwrap();
         if let Some(captures) = spotify_re.captures(msg_content) {
-            if let Some(id) = captures.get(1) {
-                return AudioSource::Spotify(id.as_str().to_string());
+            if let (Some(link), Some(id)) = (captures.get(0), captures.get(1)) {
+                let start = link_timestamp(link.as_str());
+                return AudioSource::Spotify(id.as_str().to_string(), start);
             }
         }
 
         // Check fo regular http matches.
         let https_re = Regex::new(r"https://[^\s]*").unwrap();
         if let Some(captures) = https_re.captures(msg_content) {
-            if let Some(id) = captures.get(0) {
-                return AudioSource::Url(id.as_str().to_string());
+            if let Some(link) = captures.get(0) {
+                let link = link.as_str();
+                // The fragment is only for us, don't send it to ffmpeg.
+                let url = link.split('#').next().unwrap_or(link);
+                return AudioSource::Url(url.to_string(), link_timestamp(link));
             }
         }
 
-        // Not url or spotify... must be miitopia.
-        return AudioSource::Miitopia;
+        // Not url or spotify... must be miitopia. Pick a track if they asked
+        // for one.
+        let number = query
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix('#')?.parse().ok());
+        match number {
+            Some(number) => AudioSource::Number(number),
+            None if !query.trim().is_empty() => AudioSource::Search(query.trim().to_string()),
+            None => AudioSource::Miitopia,
+        }
     }
 
     pub async fn get_track(
         &self,
         ctx_data: &Arc<RwLock<TypeMap>>,
         rng: &mut SmallRng,
-    ) -> Result<(String, f32), MiitopiaError> {
+        options: &RenderOptions,
+        channel: u64,
+    ) -> Result<Clip, MiitopiaError> {
         match self {
-            AudioSource::Miitopia => {
+            AudioSource::Miitopia | AudioSource::Search(_) | AudioSource::Number(_) => {
                 // Get our music from the data_read lock.
                 let data_read = ctx_data.read().await;
                 let tracks = data_read
@@ -65,31 +317,100 @@ impl AudioSource {
                     .read()
                     .await;
 
-                // Get a random track.
-                let index = rng.gen_range(0..tracks.len());
-                if let Some((path, track_duration)) = tracks.get_index(index) {
-                    // If the track is longer than MAX_LENGTH, return track with a random start time.
-                    let start_max = track_duration - track_duration.min(MAX_AUDIO_LENGTH);
-                    let mut start = 0.0;
-                    if start_max > 0.0 {
-                        start = rng.gen_range(0.0..start_max);
+                if tracks.is_empty() {
+                    return Err(MiitopiaError::NoTracks);
+                }
+
+                // Asking for tags picks at random from the tracks with them.
+                let pool = match self {
+                    AudioSource::Search(query) => tag_pool(&tracks, query),
+                    _ => None,
+                };
+
+                // Explicit picks, or `None` to pick at random.
+                let mut suggestions = Vec::new();
+                let index = match self {
+                    AudioSource::Number(number) => match find_number(&tracks, *number) {
+                        Some(index) => Some(index),
+                        None => return Err(MiitopiaError::TrackNotFound(format!("#{number}"))),
+                    },
+                    AudioSource::Search(_) if pool.is_some() => None,
+                    AudioSource::Search(query) => match search(&tracks, query) {
+                        Search::Found(index) => Some(index),
+                        Search::Ambiguous(matches) => {
+                            return Err(MiitopiaError::AmbiguousTrack(query.clone(), matches))
+                        }
+                        // Probably chat that mentions a track, play something
+                        // random but say what they might have meant.
+                        Search::Suggestions(matches) => {
+                            suggestions = matches;
+                            None
+                        }
+                        // Probably just chatting, play something random.
+                        Search::NotFound => {
+                            trace!("Nothing matched \"{}\", picking at random", query);
+                            None
+                        }
+                    },
+                    _ => None,
+                };
+
+                // Get a random track, one that hasn't played here lately if we
+                // keep a history.
+                let history = data_read.get::<ShuffleHistory>();
+                let index = match index {
+                    Some(index) => index,
+                    None => {
+                        let pool = match (self, pool) {
+                            (AudioSource::Search(query), Some(pool)) if pool.is_empty() => {
+                                return Err(MiitopiaError::TrackNotFound(query.clone()))
+                            }
+                            (_, Some(pool)) => pool,
+                            (_, None) => random_pool(&tracks),
+                        };
+                        if pool.is_empty() {
+                            return Err(MiitopiaError::NoTracks);
+                        }
+                        match history {
+                            Some(history) => history.pick(channel, &tracks, &pool, rng).await,
+                            None => pick_weighted(&tracks, &pool, rng),
+                        }
                     }
-                    trace!("Using {} starting at {} seconds", path.display(), start);
-                    return Ok((
+                };
+                if let Some((path, track)) = tracks.get_index(index) {
+                    let range = clip_range(options, Some(track.duration), &track.analysis, rng)
+                        .map_err(MiitopiaError::InvalidClip)?;
+                    trace!("Using {} starting at {} seconds", track, range.0);
+                    if let Some(history) = history {
+                        history.played(channel, path).await;
+                    }
+                    let mut clip = Clip::new(
                         path.to_owned().into_os_string().into_string().unwrap(),
-                        start,
-                    ));
+                        range,
+                        ClipSource::Miitopia(track.clone()),
+                    );
+                    clip.suggestions = suggestions;
+                    return Ok(clip);
                 }
-                return Err(MiitopiaError::NoTracks);
+                Err(MiitopiaError::NoTracks)
             }
-            AudioSource::Url(url) => {
+            AudioSource::Url(url, link_start) => {
                 let result = reqwest::get(url).await?;
                 trace!("Downloading \"{}\" to get mimetype.", url);
                 if let Ok(mime) = result.headers()[CONTENT_TYPE].to_str() {
                     match mime {
                         // Return the url if it's supported.
                         "audio/mpeg" | "audio/ogg" | "audio/vorbis" => {
-                            return Ok((url.to_string(), 0.0))
+                            let options = RenderOptions {
+                                start: options.start.or(*link_start),
+                                ..options.clone()
+                            };
+                            return Ok(Clip::new(
+                                url.to_string(),
+                                clip_range(&options, None, &Analysis::default(), rng)
+                                    .map_err(MiitopiaError::InvalidClip)?,
+                                ClipSource::Url(url.to_string()),
+                            ));
                         }
                         content_type => {
                             return Err(MiitopiaError::UnsupportedFileType(
@@ -98,9 +419,9 @@ impl AudioSource {
                         }
                     }
                 }
-                return Err(MiitopiaError::UnsupportedFileType("Unknown".to_string()));
+                Err(MiitopiaError::UnsupportedFileType("Unknown".to_string()))
             }
-            AudioSource::Spotify(id) => {
+            AudioSource::Spotify(id, link_start) => {
                 // Get our music from the data_read lock.
                 trace!("Getting instance of spotify.");
                 let data_read = ctx_data.read().await;
@@ -126,17 +447,243 @@ impl AudioSource {
                     Some(url_value) => match url_value {
                         Value::String(url) => {
                             trace!("Got preview_url from spotify. {:?}", url);
-                            return Ok((url.to_owned(), 0.0));
+                            let source = ClipSource::Spotify {
+                                name: json["name"].as_str().unwrap_or("Unknown").to_string(),
+                                artists: spotify_artists(&json),
+                                url: format!("https://open.spotify.com/track/{}", id),
+                            };
+                            let options = RenderOptions {
+                                start: Some(preview_start(options, *link_start)),
+                                ..options.clone()
+                            };
+                            let range = clip_range(
+                                &options,
+                                Some(SPOTIFY_PREVIEW_LENGTH),
+                                &Analysis::default(),
+                                rng,
+                            )
+                            .map_err(MiitopiaError::InvalidClip)?;
+                            Ok(Clip::new(url.to_owned(), range, source))
                         }
                         _ => {
                             warn!("Preview URL is not a string");
                             trace!("{:?}", json);
-                            return Err(MiitopiaError::Spotify(SpotifyError::NotFound));
+                            Err(MiitopiaError::Spotify(SpotifyError::NotFound))
                         }
                     },
-                    None => return Err(MiitopiaError::Spotify(SpotifyError::NotFound)),
+                    None => Err(MiitopiaError::Spotify(SpotifyError::NotFound)),
                 }
             }
         }
     }
 }
+
+/// Where to start in a spotify preview. Only the preview can be played, so
+/// the start is kept inside it.
+fn preview_start(options: &RenderOptions, link_start: Option<f32>) -> f32 {
+    let length = options.length.unwrap_or(CLIP_LENGTH);
+    options.start.or(link_start).map_or(0.0, |start| {
+        start.min(SPOTIFY_PREVIEW_LENGTH - length).max(0.0)
+    })
+}
+
+/// Read where a link says to start, like `#0:30`, `?t=30`, `#t=30` or
+/// `?t=1m30s`.
+fn link_timestamp(link: &str) -> Option<f32> {
+    let url = reqwest::Url::parse(link).ok()?;
+    let query = url
+        .query_pairs()
+        .find(|(key, _)| key == "t")
+        .map(|(_, value)| value.into_owned());
+    let fragment = url
+        .fragment()
+        .map(|fragment| fragment.strip_prefix("t=").unwrap_or(fragment).to_string());
+    [query, fragment]
+        .into_iter()
+        .flatten()
+        .find_map(|time| parse_link_time(&time))
+}
+
+/// Read a time from a link. Either a timestamp like `1:30` or `90`, or with
+/// units like `1m30s`. Media fragments can have an end too (`30,40`), only the
+/// start is used.
+fn parse_link_time(text: &str) -> Option<f32> {
+    let text = text.split(',').next()?;
+    if text.is_empty() {
+        return None;
+    }
+    if let Some(secs) = parse_timestamp(text) {
+        return Some(secs);
+    }
+
+    let mut total = 0.0;
+    let mut number = String::new();
+    for c in text.chars() {
+        let unit = match c {
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => {
+                number.push(c);
+                continue;
+            }
+        };
+        total += number.parse::<f32>().ok()? * unit;
+        number.clear();
+    }
+    match number.is_empty() {
+        true => Some(total),
+        false => None,
+    }
+}
+
+/// Join the names of the artists of a spotify track.
+fn spotify_artists(json: &Value) -> String {
+    let names: Vec<&str> = match json["artists"].as_array() {
+        Some(artists) => artists
+            .iter()
+            .filter_map(|artist| artist["name"].as_str())
+            .collect(),
+        None => vec![],
+    };
+    names.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn range(
+        start: Option<f32>,
+        length: Option<f32>,
+        total: Option<f32>,
+    ) -> Result<(f32, f32), String> {
+        range_with(start, length, total, &mut SmallRng::seed_from_u64(0))
+    }
+
+    fn range_with(
+        start: Option<f32>,
+        length: Option<f32>,
+        total: Option<f32>,
+        rng: &mut SmallRng,
+    ) -> Result<(f32, f32), String> {
+        let options = RenderOptions {
+            start,
+            length,
+            ..Default::default()
+        };
+        clip_range(&options, total, &Analysis::default(), rng)
+    }
+
+    #[test]
+    fn cuts_the_clip_asked_for() {
+        assert_eq!(range(Some(45.0), Some(7.0), Some(60.0)), Ok((45.0, 7.0)));
+        assert_eq!(range(Some(5.0), None, None), Ok((5.0, CLIP_LENGTH)));
+    }
+
+    #[test]
+    fn picks_a_start_that_fits() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let (start, duration) = range_with(None, None, Some(12.0), &mut rng).unwrap();
+            assert!(start >= 0.0 && start + duration <= 12.0);
+        }
+    }
+
+    #[test]
+    fn reads_link_times() {
+        assert_eq!(parse_link_time("90"), Some(90.0));
+        assert_eq!(parse_link_time("1:30"), Some(90.0));
+        assert_eq!(parse_link_time("1m30s"), Some(90.0));
+        assert_eq!(parse_link_time("1h2m3s"), Some(3723.0));
+        assert_eq!(parse_link_time("30,40"), Some(30.0));
+        assert_eq!(parse_link_time("1m30"), None);
+        assert_eq!(parse_link_time("chorus"), None);
+        assert_eq!(parse_link_time(""), None);
+    }
+
+    #[test]
+    fn reads_link_timestamps() {
+        let spotify = "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC";
+        assert_eq!(link_timestamp(&format!("{spotify}#0:30")), Some(30.0));
+        assert_eq!(link_timestamp(&format!("{spotify}?si=abc")), None);
+        let url = "https://www.myinstants.com/media/sounds/bruh.mp3";
+        assert_eq!(link_timestamp(&format!("{url}?t=1m30s")), Some(90.0));
+        assert_eq!(link_timestamp(&format!("{url}#t=30,40")), Some(30.0));
+        assert_eq!(link_timestamp(&format!("{url}#t=")), None);
+        assert_eq!(link_timestamp(&format!("{url}#comments")), None);
+        assert_eq!(link_timestamp(url), None);
+    }
+
+    #[test]
+    fn keeps_spotify_clips_in_the_preview() {
+        let options = |start, length| RenderOptions {
+            start,
+            length,
+            ..Default::default()
+        };
+        assert_eq!(preview_start(&options(None, None), None), 0.0);
+        assert_eq!(preview_start(&options(None, None), Some(5.0)), 5.0);
+        // The message wins over the link.
+        assert_eq!(preview_start(&options(Some(2.0), None), Some(5.0)), 2.0);
+        // Moved back so the whole clip is in the preview.
+        assert_eq!(preview_start(&options(None, None), Some(25.0)), 20.0);
+        assert_eq!(preview_start(&options(None, Some(6.0)), Some(90.0)), 24.0);
+        assert_eq!(preview_start(&options(None, Some(30.0)), Some(5.0)), 0.0);
+    }
+
+    #[test]
+    fn snaps_to_nearby_beats() {
+        let beats = [1.0, 2.0, 3.0];
+        assert_eq!(nearest_beat(&beats, 2.3), Some(2.0));
+        assert_eq!(nearest_beat(&beats, 0.8), Some(1.0));
+        assert_eq!(nearest_beat(&beats, 3.4), Some(3.0));
+        // Too far from any beat.
+        assert_eq!(nearest_beat(&beats, 5.0), None);
+        assert_eq!(nearest_beat(&[], 1.0), None);
+
+        // Random clips start and end on a beat.
+        let analysis = Analysis {
+            beats: (0..120).map(|beat| beat as f32 * 0.5).collect(),
+            ..Default::default()
+        };
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let (start, duration) =
+                clip_range(&RenderOptions::default(), Some(60.0), &analysis, &mut rng).unwrap();
+            assert_eq!(start % 0.5, 0.0, "{start}");
+            assert_eq!((start + duration) % 0.5, 0.0, "{duration}");
+        }
+    }
+
+    #[test]
+    fn picks_starts_inside_regions() {
+        let regions = [(5.0, 20.0), (40.0, 55.0)];
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let start = random_start(60.0, 10.0, &regions, &mut rng);
+            assert!(
+                regions
+                    .iter()
+                    .any(|(first, last)| start >= *first && start + 10.0 <= *last),
+                "{start}"
+            );
+        }
+        // Too short for the clip, so anywhere will do.
+        let start = random_start(60.0, 20.0, &regions, &mut rng);
+        assert!((0.0..=40.0).contains(&start));
+    }
+
+    #[test]
+    fn rejects_clips_that_dont_fit() {
+        // Past the end of the track.
+        assert!(range(Some(55.0), None, Some(60.0)).is_err());
+        assert!(range(Some(100.0), Some(5.0), Some(60.0)).is_err());
+        // Reversed ranges, like 0:52-0:45.
+        assert!(range(Some(52.0), Some(-7.0), Some(60.0)).is_err());
+        // Longer than the maximum.
+        assert!(range(Some(0.0), Some(max_clip_length() + 1.0), Some(600.0)).is_err());
+    }
+}
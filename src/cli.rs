@@ -0,0 +1,177 @@
+use std::{path::Path, sync::Arc};
+
+use human_repr::{HumanCount, HumanDuration};
+use log::{info, warn};
+use rand::{rngs::SmallRng, SeedableRng};
+use serenity::prelude::{RwLock, TypeMap};
+
+use crate::{
+    analysis::Analysis,
+    audio_source::{clip_range, AudioSource, Clip, ClipSource},
+    error::MiitopiaError,
+    library::scan_music,
+    media::Media,
+    options::{parse_timestamp, AudioMix, OutputFormat, RenderOptions},
+    processor::{apply_music, render_deadline},
+    spotify_from_env, Music,
+};
+
+const USAGE: &str = "Usage: miitopia render <input> [--track <path|url>] [--start <time>]
+    [--length <time>] [--fade-in <secs>] [--fade-out <secs>] [--mix <voice|music>]
+    [--format <webm|mp4|gif|ogg>] [--caption | --no-caption] [-o <out>]";
+
+/// Arguments for the `render` subcommand.
+struct RenderArgs {
+    input: String,
+    track: Option<String>,
+    options: RenderOptions,
+    /// Where to write the render, `miitopia.<format>` if `None`.
+    output: Option<String>,
+}
+
+impl RenderArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<RenderArgs, String> {
+        let mut input = None;
+        let mut track = None;
+        let mut options = RenderOptions::default();
+        let mut output = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--track" | "-t" => {
+                    track = Some(args.next().ok_or("--track needs a value")?);
+                }
+                "--start" | "-s" => {
+                    let value = args.next().ok_or("--start needs a value")?;
+                    let secs = parse_timestamp(&value)
+                        .ok_or_else(|| format!("Invalid start time '{value}'"))?;
+                    options.start = Some(secs);
+                }
+                "--length" | "-l" => {
+                    let value = args.next().ok_or("--length needs a value")?;
+                    let secs = parse_timestamp(&value)
+                        .ok_or_else(|| format!("Invalid length '{value}'"))?;
+                    options.length = Some(secs);
+                }
+                "--fade-in" | "--fade-out" => {
+                    let value = args.next().ok_or(format!("{arg} needs a value"))?;
+                    let secs =
+                        parse_timestamp(&value).ok_or_else(|| format!("Invalid fade '{value}'"))?;
+                    match arg.as_str() {
+                        "--fade-in" => options.fade_in = Some(secs),
+                        _ => options.fade_out = Some(secs),
+                    }
+                }
+                "--mix" | "-m" => {
+                    let value = args.next().ok_or("--mix needs a value")?;
+                    options.mix = AudioMix::from_name(&value)
+                        .ok_or_else(|| format!("Unknown mix '{value}'"))?;
+                }
+                "--format" | "-f" => {
+                    let value = args.next().ok_or("--format needs a value")?;
+                    options.format = Some(
+                        OutputFormat::from_name(&value)
+                            .ok_or_else(|| format!("Unknown format '{value}'"))?,
+                    );
+                }
+                "--caption" => options.caption = Some(true),
+                "--no-caption" => options.caption = Some(false),
+                "--output" | "-o" => {
+                    output = Some(args.next().ok_or("-o needs a value")?);
+                }
+                flag if flag.starts_with('-') => return Err(format!("Unknown option '{flag}'")),
+                _ if input.is_none() => input = Some(arg),
+                _ => return Err(format!("Unexpected argument '{arg}'")),
+            }
+        }
+
+        Ok(RenderArgs {
+            input: input.ok_or("Missing input file")?,
+            track,
+            options,
+            output,
+        })
+    }
+}
+
+/// Run the `render` subcommand. Renders a local file with the same pipeline
+/// the bot uses and writes the result to disk.
+pub async fn render(args: impl Iterator<Item = String>) {
+    let args = match RenderArgs::parse(args) {
+        Ok(args) => args,
+        Err(why) => {
+            eprintln!("{why}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(why) = run(args).await {
+        eprintln!("{why}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: RenderArgs) -> Result<(), MiitopiaError> {
+    let media = Media::from_path(Path::new(&args.input)).await?;
+
+    let mut rng = SmallRng::from_entropy();
+    let clip = match args.track {
+        // A local audio file, use it as is.
+        Some(track) if Path::new(&track).is_file() => {
+            let (start, duration) = clip_range(&args.options, None, &Analysis::default(), &mut rng)
+                .map_err(MiitopiaError::InvalidClip)?;
+            Clip {
+                source: ClipSource::Url(track.clone()),
+                audio: track,
+                start,
+                duration,
+                suggestions: Vec::new(),
+            }
+        }
+        // Anything else goes through the same AudioSource selection as the bot.
+        track => {
+            let mut data = TypeMap::new();
+            let source = match track {
+                Some(track) => AudioSource::from_msg_content(&track, &track),
+                None => AudioSource::Miitopia,
+            };
+            match source {
+                AudioSource::Miitopia | AudioSource::Search(_) | AudioSource::Number(_) => {
+                    info!("Scanning /resources/music");
+                    data.insert::<Music>(Arc::new(RwLock::new(scan_music().await.0)));
+                }
+                AudioSource::Spotify(..) => {
+                    if let Some(spotify) = spotify_from_env().await {
+                        data.insert::<crate::spotify::Spotify>(Arc::new(RwLock::new(spotify)));
+                    }
+                }
+                AudioSource::Url(..) => {}
+            }
+            source
+                .get_track(&Arc::new(RwLock::new(data)), &mut rng, &args.options, 0)
+                .await?
+        }
+    };
+
+    let job = apply_music(clip, media, &args.options, render_deadline()).await?;
+
+    if let Some(stderr) = &job.stderr {
+        warn!("ffmpeg stderr: {stderr}");
+    }
+    let format = args.options.format();
+    let output = args
+        .output
+        .unwrap_or_else(|| format!("miitopia.{}", format.extension()));
+    tokio::fs::write(&output, &job.output_file).await?;
+
+    println!(
+        "Rendered {} to {}\n\tType: {}\n\tSize: {}\n\tTime: {}\n\tTrack: {}",
+        job.source,
+        output,
+        format.mimetype(),
+        job.output_file.len().human_count_bytes(),
+        job.job_time.human_duration(),
+        job.clip,
+    );
+    Ok(())
+}
@@ -1,5 +1,7 @@
 use core::fmt;
-use std::io;
+use std::{io, time::Duration};
+
+use human_repr::{HumanCount, HumanDuration};
 
 use serenity::{
     builder::CreateMessage,
@@ -9,6 +11,7 @@ use serenity::{
     utils::colours,
 };
 
+use crate::library::Track;
 use crate::spotify::{SpotifyError};
 
 #[derive(Debug)]
@@ -20,7 +23,19 @@ pub enum MiitopiaError {
     UnsupportedFileType(String),
     Reqwest(reqwest::Error),
     NoTracks,
-    Spotify(SpotifyError)
+    Spotify(SpotifyError),
+    /// The output was still too big to upload. (size, limit) in bytes.
+    TooLarge(usize, usize),
+    /// A linked file was bigger than the limit, in bytes.
+    DownloadTooLarge(usize),
+    QueueFull,
+    Timeout(Duration),
+    /// No track has this title or number.
+    TrackNotFound(String),
+    /// The search (first) matched all of these tracks about as well.
+    AmbiguousTrack(String, Vec<Track>),
+    /// The start or length asked for can't be cut from the track.
+    InvalidClip(String),
 }
 
 impl fmt::Display for MiitopiaError {
@@ -36,6 +51,29 @@ impl fmt::Display for MiitopiaError {
             MiitopiaError::NoTracks => write!(f, "No Tracks"),
             MiitopiaError::Reqwest(e) => write!(f, "Reqwest Error: {}", e),
             MiitopiaError::Spotify(e) => write!(f, "Spotify API Error: {}", e),
+            MiitopiaError::TooLarge(size, limit) => write!(
+                f,
+                "Output Too Large: {} (limit {})",
+                size.human_count_bytes(),
+                limit.human_count_bytes()
+            ),
+            MiitopiaError::DownloadTooLarge(limit) => {
+                write!(f, "Download Too Large: over {}", limit.human_count_bytes())
+            }
+            MiitopiaError::QueueFull => write!(f, "Render Queue Full"),
+            MiitopiaError::Timeout(limit) => {
+                write!(f, "Render Timed Out after {}", limit.human_duration())
+            }
+            MiitopiaError::TrackNotFound(query) => write!(f, "Track Not Found: {}", query),
+            MiitopiaError::AmbiguousTrack(query, matches) => {
+                write!(
+                    f,
+                    "Ambiguous Track: {} matched {} tracks",
+                    query,
+                    matches.len()
+                )
+            }
+            MiitopiaError::InvalidClip(why) => write!(f, "Invalid Clip: {}", why),
         }
     }
 }
@@ -82,9 +120,38 @@ impl MiitopiaError {
                 MiitopiaError::NoTracks => em.title("🔥 No Audio Found").description("Miitopia could not find any audio."),
                 MiitopiaError::Spotify(e) => match e {
                     SpotifyError::NotFound => em.title("Preview NOT FUCKING FOUND").description("AAARRRRGGGGHHHH.... S P O T I F Y!\n\nwhat **THE FUCK** are you DOINGGGG!\nApparently according to Spotify, this song doesn't have a preview available in this market (AU). Despite it working perfectly fine right there ☝️ AND DESPITE it working INSIDE THEIR OWN FUCKING API DOCUMENTATION. Due to the lack of documentation on spotify's SHIT ASS FUCKIUNG WEAK ASS CUNT developer website (honestly pretty decent IMO) this song won't work. FML spotify is hard to deal with."),
-                    _ => em.title(format!("🌐 Spotify Error")).description(e).color(colours::css::POSITIVE)
+                    _ => em.title("🌐 Spotify Error").description(e).color(colours::css::POSITIVE)
                 },
-                
+                MiitopiaError::TooLarge(size, limit) => em.title("📦 Too Big").description(format!(
+                    "Even squished down the video came out at {}, discord only lets me upload {} here.",
+                    size.human_count_bytes(),
+                    limit.human_count_bytes()
+                )),
+                MiitopiaError::DownloadTooLarge(limit) => em.title("📦 Too Big").description(format!(
+                    "That link is over {}, too big for me to download.",
+                    limit.human_count_bytes()
+                )),
+                MiitopiaError::QueueFull => em
+                    .title("🚦 Too Busy")
+                    .description("Miitopia has too much to render right now. Try again in a bit."),
+                MiitopiaError::Timeout(limit) => em.title("⏱ Took Too Long").description(format!(
+                    "Rendering took longer than {} so I gave up.",
+                    limit.human_duration()
+                )),
+                MiitopiaError::TrackNotFound(query) => em
+                    .title("🔍 Track Not Found")
+                    .description(format!("There's no Miitopia track *{}*.", query)),
+                MiitopiaError::AmbiguousTrack(query, matches) => {
+                    let mut description = format!("*{}* could be a few tracks. Did you mean:\n", query);
+                    for track in matches {
+                        match track.number {
+                            Some(number) => description.push_str(&format!("\n`#{}` {}", number, track.name())),
+                            None => description.push_str(&format!("\n{}", track.name())),
+                        }
+                    }
+                    em.title("🤔 Which One?").description(description)
+                }
+                MiitopiaError::InvalidClip(why) => em.title("✂️ Can't Cut That").description(why),
             };
             em
         });
@@ -0,0 +1,808 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use glob::glob;
+use indexmap::IndexMap;
+use log::{debug, info, warn};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use ogg::PacketReader;
+use ogg_metadata::{read_format, AudioMetadata, OggFormat};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::SmallRng,
+    Rng,
+};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    futures::{stream, StreamExt},
+    prelude::RwLock,
+};
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::analysis::{analyse, Analysis, AnalysisError, ANALYSIS_VERSION};
+use crate::{load_or_default, CLIP_LENGTH};
+
+const MUSIC_DIR: &str = "./resources/music";
+
+/// Where we keep what we learnt about each track between restarts.
+const INDEX_PATH: &str = "./resources/music/.index.json";
+
+/// Weights, tags and exclusions for the tracks, written by hand.
+const MANIFEST_PATH: &str = "./resources/music/manifest.toml";
+
+/// How many tracks to probe at once.
+const SCAN_CONCURRENCY: usize = 8;
+
+/// How long the music directory has to be quiet before we rescan it.
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Everything we know about a track in the music directory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: PathBuf,
+    /// When the file was last modified, used to notice it has changed.
+    pub modified: SystemTime,
+    pub size: u64,
+    /// Length of the track in seconds.
+    pub duration: f32,
+    /// "vorbis" or "opus".
+    pub codec: String,
+    /// Vorbis comments with lowercase keys, like "title" and "artist".
+    pub tags: BTreeMap<String, String>,
+    /// `None` until the track has been analysed.
+    #[serde(default)]
+    pub analysis: Option<Analysis>,
+}
+
+/// A song in the music library.
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub path: PathBuf,
+    /// Length of the track in seconds.
+    pub duration: f32,
+    pub title: Option<String>,
+    /// Where the track is on the album.
+    pub number: Option<u32>,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub composer: Option<String>,
+    /// How likely the track is to be picked at random compared to the others.
+    /// `0.0` means it's only played when asked for.
+    pub weight: f32,
+    /// Lowercase tags like "battle" or "sad", from the manifest.
+    pub tags: Vec<String>,
+    /// Where the good parts and beats are. Empty if we don't know.
+    pub analysis: Analysis,
+}
+
+impl Track {
+    /// The title of the track, or the file name if it doesn't have one.
+    pub fn name(&self) -> String {
+        match &self.title {
+            Some(title) => title.clone(),
+            None => self
+                .path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+
+    /// Who to credit for the track. The composers if we know them, otherwise
+    /// the artists.
+    pub fn credits(&self) -> Option<&str> {
+        self.composer.as_deref().or(self.artist.as_deref())
+    }
+}
+
+impl From<IndexEntry> for Track {
+    fn from(mut entry: IndexEntry) -> Track {
+        // Track numbers are sometimes written as "1/336".
+        let number = entry
+            .tags
+            .get("tracknumber")
+            .or(entry.tags.get("track"))
+            .and_then(|number| number.split('/').next()?.trim().parse().ok());
+
+        Track {
+            duration: entry.duration,
+            title: entry.tags.remove("title"),
+            number,
+            album: entry.tags.remove("album"),
+            artist: entry.tags.remove("artist"),
+            composer: entry.tags.remove("composer"),
+            path: entry.path,
+            weight: 1.0,
+            tags: vec![],
+            analysis: entry.analysis.unwrap_or_default(),
+        }
+    }
+}
+
+/// The manifest, a TOML file with a table for each track that needs one:
+///
+/// ```toml
+/// [tracks."A Lively Inn.ogg"]
+/// weight = 2.0
+/// tags = ["inn", "happy"]
+///
+/// [tracks."Nintendo 3DS Home Menu Banner.ogg"]
+/// exclude = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+struct Manifest {
+    /// Keyed by file name.
+    #[serde(default)]
+    tracks: HashMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ManifestEntry {
+    weight: Option<f32>,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Never pick the track at random.
+    #[serde(default)]
+    exclude: bool,
+}
+
+impl Track {
+    fn apply_manifest(&mut self, entry: &ManifestEntry) {
+        if let Some(weight) = entry.weight {
+            self.weight = weight.max(0.0);
+        }
+        if entry.exclude {
+            self.weight = 0.0;
+        }
+        self.tags = entry.tags.iter().map(|tag| tag.to_lowercase()).collect();
+    }
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(number) = self.number {
+            write!(f, "#{} ", number)?;
+        }
+        write!(f, "{}", self.name())?;
+        if let Some(album) = &self.album {
+            write!(f, " from {}", album)?;
+        }
+        if let Some(credits) = self.credits() {
+            write!(f, " by {}", credits)?;
+        }
+        Ok(())
+    }
+}
+
+/// How well a track has to match a search to be picked, from 0 to 1.
+const SEARCH_THRESHOLD: f32 = 0.5;
+
+/// How much better than the rest the best match has to be to be picked
+/// without asking.
+const SEARCH_MARGIN: f32 = 0.25;
+
+/// How many tracks to suggest when a search is ambiguous.
+const SEARCH_SUGGESTIONS: usize = 5;
+
+/// Words too common to say anything about which track someone wants. Words
+/// shorter than 3 letters are ignored too.
+const STOPWORDS: [&str; 16] = [
+    "the", "and", "for", "are", "but", "not", "you", "this", "that", "with", "from", "what", "was",
+    "lol", "its", "all",
+];
+
+/// The result of searching the library.
+pub enum Search {
+    /// The index of the track that matched.
+    Found(usize),
+    /// Several tracks matched about as well as each other, these are the
+    /// best ones.
+    Ambiguous(Vec<Track>),
+    /// Some tracks share a word with the query, but it's probably just chat
+    /// that happens to mention one. These are the closest.
+    Suggestions(Vec<Track>),
+    NotFound,
+}
+
+/// The tracks that can be picked at random.
+pub fn random_pool(tracks: &IndexMap<PathBuf, Track>) -> Vec<usize> {
+    tracks
+        .values()
+        .enumerate()
+        .filter(|(_, track)| track.weight > 0.0)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// If every word in `query` is a tag, the tracks that can be picked at random
+/// and have all of them. `None` if it isn't a list of tags.
+pub fn tag_pool(tracks: &IndexMap<PathBuf, Track>, query: &str) -> Option<Vec<usize>> {
+    let query: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    let is_tag = |word: &String| tracks.values().any(|track| track.tags.contains(word));
+    if query.is_empty() || !query.iter().all(is_tag) {
+        return None;
+    }
+
+    Some(
+        random_pool(tracks)
+            .into_iter()
+            .filter(|index| {
+                let track = &tracks[*index];
+                query.iter().all(|word| track.tags.contains(word))
+            })
+            .collect(),
+    )
+}
+
+/// Pick one of the tracks in `pool` at random, going by their weights.
+/// `pool` must not be empty.
+pub fn pick_weighted(
+    tracks: &IndexMap<PathBuf, Track>,
+    pool: &[usize],
+    rng: &mut SmallRng,
+) -> usize {
+    let weights = pool.iter().map(|index| tracks[*index].weight);
+    match WeightedIndex::new(weights) {
+        Ok(weighted) => pool[weighted.sample(rng)],
+        // All of them are weighted 0, treat them the same.
+        Err(_) => pool[rng.gen_range(0..pool.len())],
+    }
+}
+
+/// Find a track by its number on the album.
+pub fn find_number(tracks: &IndexMap<PathBuf, Track>, number: u32) -> Option<usize> {
+    tracks
+        .values()
+        .position(|track| track.number == Some(number))
+}
+
+/// Find the track with the title that best matches `query`. Small typos and
+/// partial words are allowed. Short and common words are only used for exact
+/// matches, so chat like "the" doesn't match everything.
+pub fn search(tracks: &IndexMap<PathBuf, Track>, query: &str) -> Search {
+    let query = words(query);
+    let meaningful: Vec<&String> = query
+        .iter()
+        .filter(|word| word.chars().count() >= 3 && !STOPWORDS.contains(&word.as_str()))
+        .collect();
+    if meaningful.is_empty() {
+        // Unless it's a whole title, there's nothing to go on.
+        return match tracks
+            .values()
+            .position(|track| words(&track.name()) == query)
+        {
+            Some(index) if !query.is_empty() => Search::Found(index),
+            _ => Search::NotFound,
+        };
+    }
+
+    // (index, score, whether it's worth asking which one they meant)
+    let mut scores: Vec<(usize, f32, bool)> = tracks
+        .values()
+        .enumerate()
+        .map(|(index, track)| {
+            let title = words(&track.name());
+            // An exact match always wins.
+            if title == query {
+                return (index, f32::MAX, true);
+            }
+            let similarity = |word: &String| {
+                title
+                    .iter()
+                    .map(|title_word| word_similarity(word, title_word))
+                    .fold(0.0, f32::max)
+            };
+            let total: f32 = meaningful.iter().map(|word| similarity(word)).sum();
+            // Every word is in the title, or the message is mostly title.
+            let all_match = meaningful.iter().all(|word| similarity(word) > 0.0);
+            let hits = query.iter().filter(|word| similarity(word) > 0.0).count();
+            let sure = all_match || hits * 2 > query.len();
+            (index, total / meaningful.len() as f32, sure)
+        })
+        .filter(|(_, score, _)| *score >= SEARCH_THRESHOLD)
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let closest = || {
+        scores
+            .iter()
+            .take(SEARCH_SUGGESTIONS)
+            .filter_map(|(index, _, _)| tracks.get_index(*index))
+            .map(|(_, track)| track.clone())
+            .collect()
+    };
+    match scores.as_slice() {
+        [] => Search::NotFound,
+        [(index, _, _)] => Search::Found(*index),
+        [(index, best, _), (_, second, _), ..] if best - second >= SEARCH_MARGIN => {
+            Search::Found(*index)
+        }
+        [(_, _, true), ..] => Search::Ambiguous(closest()),
+        _ => Search::Suggestions(closest()),
+    }
+}
+
+/// Split text into lowercase words, ignoring punctuation.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// How alike two words are, from 0 to 1.
+fn word_similarity(query: &str, word: &str) -> f32 {
+    if query == word {
+        1.0
+    } else if query.len() >= 3 && word.starts_with(query) {
+        // Partially typed words.
+        0.8
+    } else if query.len() >= 4 && edit_distance(query, word) <= 1 {
+        // Typos.
+        0.7
+    } else {
+        0.0
+    }
+}
+
+/// The number of single character edits to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Scan the music directory for tracks, only probing files that are new or
+/// have changed since the last scan. Also returns the index the tracks came
+/// from, for [`watch_music`].
+pub async fn scan_music() -> (IndexMap<PathBuf, Track>, Vec<IndexEntry>) {
+    let index = scan_index().await;
+    (selectable(index.clone(), &load_manifest().await), index)
+}
+
+/// Keep `music` up to date in the background. `index` is what `music` was
+/// made from, anything in it that hasn't been analysed is analysed first. Then
+/// tracks are added, removed and replaced as the files change.
+pub fn watch_music(music: Arc<RwLock<IndexMap<PathBuf, Track>>>, index: Vec<IndexEntry>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let watcher = match watch_dir(sender) {
+        Ok(watcher) => Some(watcher),
+        Err(why) => {
+            warn!("Not watching for new music: {}", why);
+            None
+        }
+    };
+
+    let mut known = file_states(&index);
+    tokio::spawn(async move {
+        // Keep the watcher alive for as long as we're listening to it.
+        // Without one nothing is ever sent, so we stop after analysing.
+        let _watcher = watcher;
+        analyse_music(index, &music).await;
+
+        while receiver.recv().await.is_some() {
+            // Copying a file in sends a lot of events, wait for them to stop
+            // so we don't probe half written files.
+            while let Ok(Some(())) = timeout(WATCH_DEBOUNCE, receiver.recv()).await {}
+
+            let index = scan_index().await;
+            let current = file_states(&index);
+
+            for path in current.keys().filter(|path| !known.contains_key(*path)) {
+                info!("Track added: '{}'", path.display());
+            }
+            for path in known.keys().filter(|path| !current.contains_key(*path)) {
+                info!("Track removed: '{}'", path.display());
+            }
+            for (path, state) in current.iter() {
+                if known.get(path).is_some_and(|known| known != state) {
+                    info!("Track replaced: '{}'", path.display());
+                }
+            }
+
+            // Only tracks that probed fine and are long enough end up here.
+            let tracks = selectable(index.clone(), &load_manifest().await);
+            info!("Music reloaded, {} tracks", tracks.len());
+            *music.write().await = tracks;
+            known = current;
+
+            analyse_music(index, &music).await;
+        }
+    });
+}
+
+/// Send on `sender` whenever a track or the manifest changes.
+fn watch_dir(sender: mpsc::UnboundedSender<()>) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        match event {
+            // We only care that a track or the manifest changed, not how.
+            // Saving the index also shows up here, so make sure not to
+            // listen to that.
+            Ok(event)
+                if !event.kind.is_access() && event.paths.iter().any(|path| is_watched(path)) =>
+            {
+                let _ = sender.send(());
+            }
+            Ok(_) => {}
+            Err(why) => warn!("Error watching the music directory: {}", why),
+        }
+    })?;
+    watcher.watch(Path::new(MUSIC_DIR), RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+fn is_watched(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "ogg")
+        || path.file_name() == Path::new(MANIFEST_PATH).file_name()
+}
+
+/// When each file in the index was modified and how big it is.
+fn file_states(index: &[IndexEntry]) -> HashMap<PathBuf, (SystemTime, u64)> {
+    index
+        .iter()
+        .map(|entry| (entry.path.clone(), (entry.modified, entry.size)))
+        .collect()
+}
+
+/// Get the tracks from the index that can be picked, with their weights and
+/// tags from the manifest.
+fn selectable(index: Vec<IndexEntry>, manifest: &Manifest) -> IndexMap<PathBuf, Track> {
+    let mut map = IndexMap::new();
+    for entry in index {
+        // Ignore tracks that are too short.
+        if entry.duration < CLIP_LENGTH {
+            let path = entry.path.display();
+            let secs = entry.duration;
+            info!("Ignoring '{path}'. Duration: {secs}s, Minimum: {CLIP_LENGTH}.");
+            continue;
+        }
+
+        let mut track = Track::from(entry);
+        let file_name = track.path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(entry) = manifest.tracks.get(file_name.as_ref()) {
+            track.apply_manifest(entry);
+        }
+        map.insert(track.path.clone(), track);
+    }
+
+    for file_name in manifest.tracks.keys() {
+        if !map
+            .keys()
+            .any(|path| path.file_name() == Some(file_name.as_ref()))
+        {
+            warn!("The manifest mentions '{file_name}' but it isn't in the library");
+        }
+    }
+    map
+}
+
+async fn load_manifest() -> Manifest {
+    load_or_default(MANIFEST_PATH, "music manifest", toml::from_str).await
+}
+
+/// Probe everything in the music directory (using the saved index where we
+/// can) and save the index again. Tracks keep their last analysis, see
+/// [`analyse_music`] for bringing it up to date.
+pub async fn scan_index() -> Vec<IndexEntry> {
+    let mut cached = load_index().await;
+
+    let paths: Vec<PathBuf> = glob(&format!("{MUSIC_DIR}/*.ogg"))
+        .expect("Failed to read glob pattern for music")
+        .flatten()
+        .collect();
+
+    // Probe the files in parallel, keeping them in order.
+    let entries: Vec<IndexEntry> = stream::iter(paths)
+        .map(|path| {
+            let cached = cached.remove(&path);
+            async move {
+                let metadata = match tokio::fs::metadata(&path).await {
+                    Ok(metadata) => metadata,
+                    Err(why) => {
+                        warn!("Failed to read '{}': {}", path.display(), why);
+                        return None;
+                    }
+                };
+                let modified = metadata.modified().ok()?;
+                let size = metadata.len();
+
+                // Reuse what we know if the file hasn't changed.
+                let entry = match cached {
+                    Some(entry) if entry.modified == modified && entry.size == size => entry,
+                    _ => {
+                        debug!("Probing '{}'", path.display());
+                        tokio::task::spawn_blocking(move || probe(path, modified, size))
+                            .await
+                            .ok()
+                            .flatten()?
+                    }
+                };
+                Some(entry)
+            }
+        })
+        .buffered(SCAN_CONCURRENCY)
+        .filter_map(|entry| async move { entry })
+        .collect()
+        .await;
+
+    if let Err(why) = save_index(&entries).await {
+        warn!("Failed to save the music index: {}", why);
+    }
+    entries
+}
+
+/// If `entry` hasn't been analysed, or was analysed by an older version.
+fn needs_analysis(entry: &IndexEntry) -> bool {
+    entry
+        .analysis
+        .as_ref()
+        .is_none_or(|analysis| analysis.version < ANALYSIS_VERSION)
+}
+
+/// Analyse the tracks in `index` that need it, save the index and give the
+/// tracks in `music` their new analysis. This listens to every track with
+/// ffmpeg, so it can take a long time.
+async fn analyse_music(index: Vec<IndexEntry>, music: &RwLock<IndexMap<PathBuf, Track>>) {
+    let pending = index.iter().filter(|entry| needs_analysis(entry)).count();
+    if pending == 0 {
+        return;
+    }
+    info!("Analysing {pending} tracks");
+
+    let index: Vec<IndexEntry> = stream::iter(index)
+        .map(|mut entry| async move {
+            if needs_analysis(&entry) {
+                debug!("Analysing '{}'", entry.path.display());
+                match analyse(&entry.path).await {
+                    Ok(analysis) => entry.analysis = Some(analysis),
+                    // Remember broken tracks, so they aren't analysed again
+                    // on every scan.
+                    Err(AnalysisError::Ffmpeg(why)) => {
+                        warn!("Failed to analyse '{}': {}", entry.path.display(), why);
+                        entry.analysis = Some(Analysis {
+                            version: ANALYSIS_VERSION,
+                            ..Default::default()
+                        });
+                    }
+                    // Probably not the track's fault, try again next time.
+                    Err(why) => warn!("Failed to analyse '{}': {}", entry.path.display(), why),
+                }
+            }
+            entry
+        })
+        .buffered(SCAN_CONCURRENCY)
+        .collect()
+        .await;
+
+    if let Err(why) = save_index(&index).await {
+        warn!("Failed to save the music index: {}", why);
+    }
+
+    let mut music = music.write().await;
+    for entry in index {
+        if let (Some(track), Some(analysis)) = (music.get_mut(&entry.path), entry.analysis) {
+            track.analysis = analysis;
+        }
+    }
+    info!("Finished analysing {pending} tracks");
+}
+
+/// Read the duration, codec and tags of an ogg file.
+fn probe(path: PathBuf, modified: SystemTime, size: u64) -> Option<IndexEntry> {
+    // Open the file and read the formats and get the first item.
+    let mut file = std::fs::File::open(&path).ok()?;
+    let formats = read_format(&mut file).ok()?;
+
+    // Get the duration if that format supports a duration.
+    let (codec, duration) = match formats.first()? {
+        OggFormat::Vorbis(track) => ("vorbis", track.get_duration()?),
+        OggFormat::Opus(track) => ("opus", track.get_duration()?),
+        _ => {
+            warn!("'{}' is not vorbis or opus", path.display());
+            return None;
+        }
+    };
+
+    let tags = match read_tags(&path) {
+        Ok(tags) => tags,
+        Err(why) => {
+            warn!("Failed to read tags from '{}': {}", path.display(), why);
+            BTreeMap::new()
+        }
+    };
+
+    Some(IndexEntry {
+        path,
+        modified,
+        size,
+        duration: duration.as_secs_f32(),
+        codec: codec.to_string(),
+        tags,
+        analysis: None,
+    })
+}
+
+/// Read the vorbis comments from the comment header of the first stream in
+/// an ogg file. Works for both vorbis and opus.
+fn read_tags(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let file = std::fs::File::open(path)?;
+    let mut reader = PacketReader::new(io::BufReader::new(file));
+
+    // The comment header is the second packet of the stream.
+    let mut serial = None;
+    let mut packet = None;
+    while let Some(next) = reader
+        .read_packet()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    {
+        match serial {
+            None => serial = Some(next.stream_serial),
+            Some(serial) if serial == next.stream_serial => {
+                packet = Some(next.data);
+                break;
+            }
+            Some(_) => {}
+        }
+    }
+    let packet = packet.unwrap_or_default();
+
+    // Skip the packet type.
+    let mut data = if let Some(data) = packet.strip_prefix(b"\x03vorbis") {
+        data
+    } else if let Some(data) = packet.strip_prefix(b"OpusTags") {
+        data
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no comment header",
+        ));
+    };
+
+    // Everything is a little endian u32 length followed by that many bytes.
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = (data.get(..len)?, data.get(len..)?);
+        *data = rest;
+        Some(taken)
+    }
+    fn take_u32(data: &mut &[u8]) -> Option<usize> {
+        let bytes = take(data, 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+    }
+
+    let mut tags = BTreeMap::new();
+    let _vendor = take_u32(&mut data).and_then(|len| take(&mut data, len));
+    let count = take_u32(&mut data).unwrap_or_default();
+    for _ in 0..count {
+        let Some(comment) = take_u32(&mut data).and_then(|len| take(&mut data, len)) else {
+            break;
+        };
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            let key = key.to_lowercase();
+            // Skip embedded pictures, they're huge.
+            if key == "metadata_block_picture" {
+                continue;
+            }
+            // Some tags (like artist) can be repeated.
+            tags.entry(key)
+                .and_modify(|existing: &mut String| {
+                    existing.push_str("; ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    Ok(tags)
+}
+
+async fn load_index() -> HashMap<PathBuf, IndexEntry> {
+    let entries: Vec<IndexEntry> =
+        load_or_default(INDEX_PATH, "music index", |json| serde_json::from_str(json)).await;
+    entries
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect()
+}
+
+async fn save_index(entries: &[IndexEntry]) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(entries)?;
+    tokio::fs::write(INDEX_PATH, json).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(titles: &[&str]) -> IndexMap<PathBuf, Track> {
+        titles
+            .iter()
+            .map(|title| {
+                let track = Track::from(IndexEntry {
+                    path: PathBuf::from(format!("{title}.ogg")),
+                    modified: SystemTime::UNIX_EPOCH,
+                    size: 0,
+                    duration: 60.0,
+                    codec: "vorbis".to_string(),
+                    tags: BTreeMap::from([("title".to_string(), title.to_string())]),
+                    analysis: None,
+                });
+                (track.path.clone(), track)
+            })
+            .collect()
+    }
+
+    fn found(search: Search) -> Option<usize> {
+        match search {
+            Search::Found(index) => Some(index),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn counts_edits() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "inn"), 3);
+        assert_eq!(edit_distance("inn", "inn"), 0);
+        assert_eq!(edit_distance("lively", "lovely"), 1);
+    }
+
+    #[test]
+    fn scores_words() {
+        assert_eq!(word_similarity("inn", "inn"), 1.0);
+        assert_eq!(word_similarity("liv", "lively"), 0.8);
+        assert_eq!(word_similarity("li", "lively"), 0.0);
+        assert_eq!(word_similarity("lovely", "lively"), 0.7);
+        // Too short to guess at typos.
+        assert_eq!(word_similarity("inm", "inn"), 0.0);
+    }
+
+    #[test]
+    fn finds_tracks_by_title() {
+        let tracks = library(&["A Lively Inn", "Battle Theme", "Battle Theme 2"]);
+        assert_eq!(found(search(&tracks, "lively inn")), Some(0));
+        assert_eq!(found(search(&tracks, "livly in")), Some(0));
+        assert_eq!(found(search(&tracks, "Battle Theme 2")), Some(2));
+        assert!(
+            matches!(search(&tracks, "battle"), Search::Ambiguous(matches) if matches.len() == 2)
+        );
+    }
+
+    #[test]
+    fn suggests_tracks_mentioned_in_chat() {
+        let tracks = library(&["A Lively Inn", "Battle Theme", "Battle Theme 2"]);
+        assert!(matches!(
+            search(&tracks, "look at this battle"),
+            Search::Suggestions(matches) if matches.len() == 2
+        ));
+        // Mostly title words is still worth asking about.
+        assert!(matches!(
+            search(&tracks, "battle theme pls"),
+            Search::Ambiguous(matches) if matches.len() == 2
+        ));
+    }
+
+    #[test]
+    fn ignores_chat() {
+        let tracks = library(&["A Lively Inn", "The Dark Lord", "A Fun Day"]);
+        for query in ["the", "a", "lol", "a the", "", "!!"] {
+            assert!(
+                matches!(search(&tracks, query), Search::NotFound),
+                "{query}"
+            );
+        }
+    }
+}
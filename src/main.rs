@@ -1,28 +1,35 @@
-use std::borrow::Cow;
 use std::env;
-use std::path::PathBuf;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 
-use audio_source::AudioSource;
-use human_repr::{HumanCount, HumanDuration};
 use indexmap::IndexMap;
-use log::{debug, error, info, trace, warn};
-use processor::{apply_music, scan_music};
-use rand::prelude::SmallRng;
-use rand::SeedableRng;
-use regex::Regex;
+use log::{debug, error, info, warn};
+use library::{scan_music, Track};
 use serenity::http::CacheHttp;
-use serenity::model::channel::{AttachmentType, Message, MessageReference};
+use serenity::model::channel::{Message, MessageReference};
 use serenity::model::gateway::Ready;
 use serenity::model::prelude::Reaction;
-use serenity::{async_trait, futures, prelude::*};
+use serenity::{async_trait, prelude::*};
 
+mod analysis;
 mod audio_source;
+mod cli;
 mod error;
+mod library;
+mod media;
+mod options;
 mod processor;
+mod queue;
+mod settings;
+mod shuffle;
+mod slideshow;
 mod spotify;
 
-const MAX_AUDIO_LENGTH: f32 = 10.0;
+/// How long a clip is when the message doesn't say, in seconds. Tracks shorter
+/// than this are left out of the library.
+const CLIP_LENGTH: f32 = 10.0;
 
 struct Handler;
 
@@ -100,34 +107,29 @@ impl EventHandler for Handler {
 #[tokio::main]
 async fn main() {
     env_logger::init();
+
+    // Render local files without connecting to discord.
+    let mut args = env::args().skip(1);
+    if args.next().as_deref() == Some("render") {
+        cli::render(args).await;
+        return;
+    }
+
     // Configure the client with your Discord bot token in the environment.
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
     // Set gateway intents, which decides what events the bot will be notified about
-    let intents = GatewayIntents::GUILD_MESSAGES
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT
         | GatewayIntents::GUILD_MESSAGE_REACTIONS;
 
     // Get a spotify token.
-    let client_id = env::var("SPOTIFY_ID");
-    let spotify = match client_id {
-        Ok(client_id) => {
-            let client_secret = env::var("SPOTIFY_SECRET")
-                .expect("If SPOTIFY_ID is provided SPOTIFY_SECRET is required");
-
-            match spotify::Spotify::from_credentials(client_id, client_secret).await {
-                Ok(spotify) => Some(spotify),
-                Err(e) => {
-                    panic!("Spotify Error: {}", e);
-                }
-            }
-        }
-        Err(_) => None,
-    };
+    let spotify = spotify_from_env().await;
 
     // Scan all our music
     info!("Scanning /resources/music");
-    let music = scan_music();
-    if music.len() > 0 {
+    let (music, index) = scan_music().await;
+    if !music.is_empty() {
         info!("Found {} tracks", music.len(),);
     } else {
         error!("no tracks found.");
@@ -144,7 +146,20 @@ async fn main() {
     {
         // Add our music and spotify to the context data.
         let mut data = client.data.write().await;
-        data.insert::<Music>(Arc::new(RwLock::new(music)));
+        let music = Arc::new(RwLock::new(music));
+        // Analysing new tracks can take a while, do it once we're online.
+        library::watch_music(music.clone(), index);
+        data.insert::<Music>(music);
+        data.insert::<queue::RenderQueue>(queue::RenderQueue::from_env());
+        let settings = settings::Settings::load().await;
+        if settings.any_captions() && !Path::new(processor::CAPTION_FONT).is_file() {
+            warn!(
+                "Captions are turned on but there's no font at {}, they'll be left off",
+                processor::CAPTION_FONT
+            );
+        }
+        data.insert::<settings::Settings>(Arc::new(settings));
+        data.insert::<shuffle::ShuffleHistory>(Arc::new(shuffle::ShuffleHistory::load().await));
         if let Some(spotify) = spotify {
             data.insert::<spotify::Spotify>(Arc::new(RwLock::new(spotify)));
         }
@@ -158,8 +173,59 @@ async fn main() {
     }
 }
 
+/// Get a spotify token if the `SPOTIFY_ID` env var is set.
+async fn spotify_from_env() -> Option<spotify::Spotify> {
+    let client_id = env::var("SPOTIFY_ID").ok()?;
+    let client_secret =
+        env::var("SPOTIFY_SECRET").expect("If SPOTIFY_ID is provided SPOTIFY_SECRET is required");
+
+    match spotify::Spotify::from_credentials(client_id, client_secret).await {
+        Ok(spotify) => Some(spotify),
+        Err(e) => {
+            panic!("Spotify Error: {}", e);
+        }
+    }
+}
+
+/// Read and parse an env var, warning if it's set to something we can't parse.
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Ignoring {name}, '{value}' is not valid");
+            None
+        }
+    }
+}
+
+/// Read and parse the file at `path`, or use the default if it's missing or
+/// can't be parsed. `what` is what the file is called in the logs.
+async fn load_or_default<T, E>(
+    path: &str,
+    what: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> T
+where
+    T: Default,
+    E: Display,
+{
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(why) => {
+            debug!("No {what} to load: {why}");
+            return T::default();
+        }
+    };
+
+    parse(&text).unwrap_or_else(|why| {
+        warn!("Ignoring invalid {what}: {why}");
+        T::default()
+    })
+}
+
 struct Music;
 
 impl TypeMapKey for Music {
-    type Value = Arc<RwLock<IndexMap<PathBuf, f32>>>;
+    type Value = Arc<RwLock<IndexMap<PathBuf, Track>>>;
 }
@@ -0,0 +1,266 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use log::trace;
+use reqwest::header::CONTENT_TYPE;
+use serenity::model::{channel::Embed, prelude::Attachment};
+
+use crate::error::MiitopiaError;
+
+/// The biggest file we'll download from a link, in bytes. Attachments are
+/// already limited by discord, links could be anything.
+const MAX_DOWNLOAD_SIZE: usize = 50 * 1024 * 1024;
+
+/// How long downloading a link can take.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A file to add music to. Everything the processor needs to know about its
+/// input, no matter where it came from.
+pub struct Media {
+    pub bytes: Vec<u8>,
+    pub mimetype: String,
+    pub filename: String,
+    /// Where the media came from, if it came from the internet.
+    pub url: Option<String>,
+}
+
+impl Media {
+    /// Download a discord attachment.
+    pub async fn from_attachment(attachment: &Attachment) -> Result<Media, MiitopiaError> {
+        // Get the mimetype of the attachment.
+        let mimetype = match &attachment.content_type {
+            Some(mimetype) => mimetype.clone(),
+            None => return Err(MiitopiaError::InvalidFileType),
+        };
+
+        Ok(Media {
+            bytes: attachment.download().await?,
+            mimetype,
+            filename: attachment.filename.clone(),
+            url: Some(attachment.url.clone()),
+        })
+    }
+
+    /// Download a file from the web, up to [`MAX_DOWNLOAD_SIZE`]. The
+    /// mimetype is taken from the `Content-Type` header.
+    pub async fn from_url(url: &str) -> Result<Media, MiitopiaError> {
+        trace!("Downloading media from \"{}\"", url);
+        let mut response = reqwest::Client::builder()
+            .timeout(DOWNLOAD_TIMEOUT)
+            .build()?
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // Don't start on anything that says it's too big.
+        if response
+            .content_length()
+            .is_some_and(|len| len > MAX_DOWNLOAD_SIZE as u64)
+        {
+            return Err(MiitopiaError::DownloadTooLarge(MAX_DOWNLOAD_SIZE));
+        }
+
+        let mimetype = match response.headers().get(CONTENT_TYPE) {
+            // Drop parameters like "; charset=utf-8".
+            Some(mime) => match mime.to_str() {
+                Ok(mime) => mime
+                    .split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+                Err(_) => return Err(MiitopiaError::InvalidFileType),
+            },
+            None => return Err(MiitopiaError::InvalidFileType),
+        };
+
+        // Use the last part of the path as the filename.
+        let filename = response
+            .url()
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default()
+            .to_string();
+
+        // The length can be missing or wrong, so keep counting.
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if bytes.len() + chunk.len() > MAX_DOWNLOAD_SIZE {
+                return Err(MiitopiaError::DownloadTooLarge(MAX_DOWNLOAD_SIZE));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        Ok(Media {
+            bytes,
+            mimetype,
+            filename,
+            url: Some(url.to_string()),
+        })
+    }
+
+    /// Read a file from disk. The mimetype is guessed from the extension.
+    pub async fn from_path(path: &Path) -> Result<Media, MiitopiaError> {
+        let mimetype = match mime_from_path(path) {
+            Some(mime) => mime.to_string(),
+            None => return Err(MiitopiaError::InvalidFileType),
+        };
+
+        Ok(Media {
+            bytes: tokio::fs::read(path).await?,
+            mimetype,
+            filename: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            url: None,
+        })
+    }
+
+    /// Get the url of the image or video inside an embed, if it has one we
+    /// can use. Discord's copy is used when it has one, so we aren't
+    /// downloading from whoever posted the link.
+    pub fn embed_url(embed: &Embed) -> Option<&str> {
+        match embed.kind.as_deref()? {
+            // Images posted as links are stored as the thumbnail.
+            "image" => embed
+                .thumbnail
+                .as_ref()
+                .map(|thumbnail| thumbnail.proxy_url.as_deref().unwrap_or(&thumbnail.url))
+                .or_else(|| {
+                    embed
+                        .image
+                        .as_ref()
+                        .map(|image| image.proxy_url.as_deref().unwrap_or(&image.url))
+                }),
+            // Gifs from tenor and giphy are actually videos.
+            "gifv" => embed
+                .video
+                .as_ref()
+                .map(|video| video.proxy_url.as_deref().unwrap_or(&video.url)),
+            _ => None,
+        }
+    }
+
+    /// If this is a single picture, rather than an animation or video.
+    pub fn is_still(&self) -> bool {
+        is_still(&self.mimetype)
+    }
+
+    /// Describe where the media came from, the url if we have one or the
+    /// filename.
+    pub fn origin(&self) -> &str {
+        self.url.as_deref().unwrap_or(&self.filename)
+    }
+}
+
+/// If `mimetype` is a kind of single picture, rather than an animation or
+/// video.
+pub fn is_still(mimetype: &str) -> bool {
+    matches!(
+        mimetype,
+        "image/png" | "image/jpeg" | "image/webp" | "image/bmp"
+    )
+}
+
+/// The links in `text` that go by their extension are pictures or videos.
+/// Discord fills in the embeds for links after the message is sent, so these
+/// are all we have to go on at first.
+pub fn media_links(text: &str) -> Vec<&str> {
+    text.split_whitespace()
+        // Links wrapped in <> aren't embedded, but are still links.
+        .map(|word| word.trim_start_matches('<').trim_end_matches('>'))
+        .filter(|word| word.starts_with("https://"))
+        .filter(|link| {
+            reqwest::Url::parse(link)
+                .ok()
+                .and_then(|url| mime_from_path(Path::new(url.path())))
+                .is_some()
+        })
+        .collect()
+}
+
+/// Guess the mimetype of a local file from its extension.
+pub fn mime_from_path(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        "bmp" => Some("image/bmp"),
+        "gif" => Some("image/gif"),
+        "webm" => Some("video/webm"),
+        "mp4" | "m4v" => Some("video/mp4"),
+        "mov" => Some("video/quicktime"),
+        "mkv" => Some("video/x-matroska"),
+        _ => None,
+    }
+}
+
+/// A file in the temp directory that is deleted when it's dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Write `bytes` to a new temporary file.
+    pub async fn create(bytes: &[u8]) -> io::Result<TempFile> {
+        TempFile::with_extension(bytes, "").await
+    }
+
+    /// Write `bytes` to a new temporary file ending in `.extension`, for when
+    /// ffmpeg goes by the name to work out what's in it.
+    pub async fn with_extension(bytes: &[u8], extension: &str) -> io::Result<TempFile> {
+        // Count up so jobs running at the same time never share a file.
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut name = format!(
+            "miitopia-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        if !extension.is_empty() {
+            name = format!("{name}.{extension}");
+        }
+
+        let file = TempFile {
+            path: std::env::temp_dir().join(name),
+        };
+        tokio::fs::write(&file.path, bytes).await?;
+        Ok(file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(why) = std::fs::remove_file(&self.path) {
+            log::warn!("Failed to remove {}: {}", self.path.display(), why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_linked_pictures() {
+        let text = "@miitopia https://example.com/cat.PNG?size=large look \
+            <https://example.com/dog.gif> https://example.com/song.mp3 https://example.com/";
+        assert_eq!(
+            media_links(text),
+            [
+                "https://example.com/cat.PNG?size=large",
+                "https://example.com/dog.gif"
+            ]
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use serde::Deserialize;
+
+/// What to do with the audio already in a video.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AudioMix {
+    /// Throw the original audio away and only play the soundtrack.
+    #[default]
+    Replace,
+    /// Keep the original audio and duck the soundtrack underneath it.
+    /// Good for videos with speech.
+    Voice,
+    /// Keep the original audio but duck it underneath the soundtrack.
+    Music,
+}
+
+impl AudioMix {
+    pub fn from_name(name: &str) -> Option<AudioMix> {
+        match name {
+            "replace" | "none" => Some(AudioMix::Replace),
+            "voice" | "original" => Some(AudioMix::Voice),
+            "music" | "soundtrack" => Some(AudioMix::Music),
+            _ => None,
+        }
+    }
+}
+
+/// What kind of file to render.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// VP9 video, plays almost everywhere discord does.
+    #[default]
+    Webm,
+    /// H.264 video with AAC audio, for iOS and sharing to other apps.
+    Mp4,
+    /// A silent animated gif.
+    Gif,
+    /// Just the audio, as opus.
+    Ogg,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "webm" | "vp9" => Some(OutputFormat::Webm),
+            "mp4" | "h264" => Some(OutputFormat::Mp4),
+            "gif" => Some(OutputFormat::Gif),
+            "ogg" | "opus" | "audio" => Some(OutputFormat::Ogg),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Webm => "webm",
+            OutputFormat::Mp4 => "mp4",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Ogg => "ogg",
+        }
+    }
+
+    pub fn mimetype(&self) -> &'static str {
+        match self {
+            OutputFormat::Webm => "video/webm",
+            OutputFormat::Mp4 => "video/mp4",
+            OutputFormat::Gif => "image/gif",
+            OutputFormat::Ogg => "audio/ogg",
+        }
+    }
+
+    pub fn has_video(&self) -> bool {
+        *self != OutputFormat::Ogg
+    }
+
+    pub fn has_audio(&self) -> bool {
+        *self != OutputFormat::Gif
+    }
+
+    /// If the video encoder can be told a bitrate to aim for. gifs can only
+    /// be made smaller by shrinking them.
+    pub fn has_video_bitrate(&self) -> bool {
+        *self != OutputFormat::Gif
+    }
+}
+
+/// Per message settings for how a render should be done.
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    pub mix: AudioMix,
+    /// The biggest the output can be in bytes, or `None` for no limit.
+    pub max_size: Option<usize>,
+    /// Where to start in the track, in seconds. Random if `None`.
+    pub start: Option<f32>,
+    /// How long the clip should be, in seconds.
+    pub length: Option<f32>,
+    /// How long the soundtrack fades in for, in seconds.
+    pub fade_in: Option<f32>,
+    /// How long the soundtrack fades out for, in seconds.
+    pub fade_out: Option<f32>,
+    /// What kind of file to render, the guild's default if `None`.
+    pub format: Option<OutputFormat>,
+    /// Put every picture in the message into one video instead of rendering
+    /// them one at a time.
+    pub slideshow: bool,
+    /// Show the track's title over the start of the video, the guild's
+    /// default if `None`.
+    pub caption: Option<bool>,
+}
+
+/// How long the soundtrack fades in and out for when the message doesn't say.
+/// Just enough to stop it clicking.
+const FADE_IN: f32 = 0.1;
+const FADE_OUT: f32 = 0.5;
+
+impl RenderOptions {
+    pub fn fade_in(&self) -> f32 {
+        self.fade_in.unwrap_or(FADE_IN)
+    }
+
+    pub fn fade_out(&self) -> f32 {
+        self.fade_out.unwrap_or(FADE_OUT)
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format.unwrap_or_default()
+    }
+
+    pub fn caption(&self) -> bool {
+        self.caption.unwrap_or(false)
+    }
+
+    /// Read the options out of a message. Options are words like `mix`,
+    /// `mix=music`, `start=1:23`, `len=6`, `fade=1`, `mp4`, `slideshow`,
+    /// `caption` or a range like `0:45-0:52`.
+    ///
+    /// Also returns the rest of the message, without the options, mentions,
+    /// emoji or links. This is what's left to search for a track with.
+    pub fn from_msg_content(msg_content: &str) -> (RenderOptions, String) {
+        let mut options = RenderOptions::default();
+        let mut rest = vec![];
+
+        for original in msg_content.split_whitespace() {
+            let word = original.to_lowercase();
+            if let Some((start, end)) = parse_range(&word) {
+                options.start = Some(start);
+                options.length = Some(end - start);
+                continue;
+            }
+
+            let (key, value) = match word.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (word.as_str(), None),
+            };
+
+            match key {
+                "mix" => {
+                    // Plain "mix" keeps the original audio on top.
+                    match value.map(AudioMix::from_name) {
+                        None => options.mix = AudioMix::Voice,
+                        Some(Some(mix)) => options.mix = mix,
+                        Some(None) => log::debug!("Ignoring unknown mix '{word}'"),
+                    }
+                }
+                "start" | "ss" => match value.and_then(parse_timestamp) {
+                    Some(secs) => options.start = Some(secs),
+                    None => log::debug!("Ignoring invalid start '{word}'"),
+                },
+                "len" | "length" => match value.and_then(parse_timestamp) {
+                    Some(secs) => options.length = Some(secs),
+                    None => log::debug!("Ignoring invalid length '{word}'"),
+                },
+                "fade" | "fadein" | "fadeout" => match value.and_then(parse_timestamp) {
+                    Some(secs) => {
+                        if key != "fadeout" {
+                            options.fade_in = Some(secs);
+                        }
+                        if key != "fadein" {
+                            options.fade_out = Some(secs);
+                        }
+                    }
+                    None => log::debug!("Ignoring invalid fade '{word}'"),
+                },
+                "nofade" => {
+                    options.fade_in = Some(0.0);
+                    options.fade_out = Some(0.0);
+                }
+                "slideshow" if value.is_none() => options.slideshow = true,
+                // The same flags as the command line work too.
+                "caption" | "--caption" if value.is_none() => options.caption = Some(true),
+                "nocaption" | "--no-caption" if value.is_none() => options.caption = Some(false),
+                "format" => match value.and_then(OutputFormat::from_name) {
+                    Some(format) => options.format = Some(format),
+                    None => log::debug!("Ignoring unknown format '{word}'"),
+                },
+                // The file extensions work on their own too.
+                "webm" | "mp4" | "gif" | "ogg" if value.is_none() => {
+                    options.format = OutputFormat::from_name(key);
+                }
+                // Mentions, emoji and links aren't part of a search.
+                _ if word.starts_with('@') || word.starts_with('<') => {}
+                _ if word.starts_with("http://") || word.starts_with("https://") => {}
+                _ => rest.push(original),
+            }
+        }
+
+        (options, rest.join(" "))
+    }
+}
+
+/// Read a time like `83`, `1:23`, `1:23.5` or `1:01:23` as seconds.
+pub fn parse_timestamp(text: &str) -> Option<f32> {
+    let mut parts = text.rsplit(':');
+    let secs: f32 = parts.next()?.parse().ok()?;
+    if !secs.is_finite() || secs < 0.0 {
+        return None;
+    }
+
+    let mut total = secs;
+    let mut unit = 60.0;
+    for (i, part) in parts.enumerate() {
+        // Nothing bigger than hours.
+        if i > 1 {
+            return None;
+        }
+        total += part.parse::<u32>().ok()? as f32 * unit;
+        unit *= 60.0;
+    }
+    Some(total)
+}
+
+/// Read a range like `0:45-0:52` as (start, end) in seconds. At least one side
+/// needs a `:` so numbers like `3-4` in a chat message are left alone.
+fn parse_range(word: &str) -> Option<(f32, f32)> {
+    let (start, end) = word.split_once('-')?;
+    if !start.contains(':') && !end.contains(':') {
+        return None;
+    }
+    Some((parse_timestamp(start)?, parse_timestamp(end)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("83"), Some(83.0));
+        assert_eq!(parse_timestamp("1:23"), Some(83.0));
+        assert_eq!(parse_timestamp("1:23.5"), Some(83.5));
+        assert_eq!(parse_timestamp("1:01:23"), Some(3683.0));
+        assert_eq!(parse_timestamp("1:1:01:23"), None);
+        assert_eq!(parse_timestamp("-5"), None);
+        assert_eq!(parse_timestamp("inn"), None);
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("0:45-0:52"), Some((45.0, 52.0)));
+        assert_eq!(parse_range("45-0:52"), Some((45.0, 52.0)));
+        // Reversed ranges are left for the clip to reject.
+        assert_eq!(parse_range("0:52-0:45"), Some((52.0, 45.0)));
+        // Plain numbers are probably just chat.
+        assert_eq!(parse_range("3-4"), None);
+    }
+
+    #[test]
+    fn reads_options_from_messages() {
+        let (options, query) = RenderOptions::from_msg_content("lively inn 0:45-0:52 mp4");
+        assert_eq!(options.start, Some(45.0));
+        assert_eq!(options.length, Some(7.0));
+        assert_eq!(options.format, Some(OutputFormat::Mp4));
+        assert_eq!(query, "lively inn");
+
+        let (options, query) = RenderOptions::from_msg_content("start=1:01:23 len=6 3-4");
+        assert_eq!(options.start, Some(3683.0));
+        assert_eq!(options.length, Some(6.0));
+        assert_eq!(query, "3-4");
+
+        let (options, _) = RenderOptions::from_msg_content("0:52-0:45");
+        assert_eq!(options.length, Some(-7.0));
+
+        let (options, query) = RenderOptions::from_msg_content("--caption lively inn");
+        assert_eq!(options.caption, Some(true));
+        assert_eq!(query, "lively inn");
+        let (options, query) = RenderOptions::from_msg_content("--no-caption");
+        assert_eq!(options.caption, Some(false));
+        assert_eq!(query, "");
+    }
+}
@@ -1,200 +1,666 @@
 use std::{
     borrow::Cow,
-    io::Write,
-    path::PathBuf,
+    io,
+    path::Path,
     process::Stdio,
+    sync::OnceLock,
     time::{Duration, Instant},
 };
 
 use ffmpeg_cli::{FfmpegBuilder, File, Parameter};
-use glob::glob;
 use human_repr::{HumanCount, HumanDuration};
-use indexmap::IndexMap;
 use log::debug;
-use ogg_metadata::{read_format, AudioMetadata};
 use rand::{rngs::SmallRng, SeedableRng};
 use serenity::{
     futures,
-    http::CacheHttp,
-    model::prelude::{Attachment, AttachmentType, Message, MessageReference},
+    model::prelude::{Attachment, AttachmentType, Message, PremiumTier},
     prelude::*,
 };
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout_at,
+};
 
-use crate::{audio_source::AudioSource, error::MiitopiaError, MAX_AUDIO_LENGTH};
-
-// TODO: Make this async.
-pub fn scan_music() -> IndexMap<PathBuf, f32> {
-    let mut map = IndexMap::new();
-
-    // Iterate over all .ogg files in the music directory.
-    for entry in glob("./resources/music/*.ogg").expect("Failed to read glob pattern for music") {
-        if let Ok(path) = entry {
-            // Clone the path because opening the file consumes our path.
-            let path_clone = path.clone();
-
-            // Open the file and read the formats and get the first item.
-            if let Ok(mut file) = std::fs::File::open(path) {
-                if let Ok(formats) = read_format(&mut file) {
-                    let format = &formats[0];
-
-                    // Get the duration if that format supports a duration.
-                    if let Some(duration) = match format {
-                        ogg_metadata::OggFormat::Vorbis(track) => track.get_duration(),
-                        ogg_metadata::OggFormat::Opus(track) => track.get_duration(),
-                        _ => None,
-                    } {
-                        let secs = duration.as_secs_f32();
-
-                        // Ignore tracks that are too short.
-                        if secs < MAX_AUDIO_LENGTH {
-                            let path = path_clone.display();
-                            log::info!(
-                                "Ignoring '{path}'. Duration: {secs}s, Minimum: {MAX_AUDIO_LENGTH}."
-                            );
-                        } else if !map.contains_key(&path_clone) {
-                            // Insert our path if it doesn't already exist.
-                            map.insert(path_clone, secs);
-                        }
-                    }
-                }
+use crate::{
+    analysis::{Loudness, LOUDNORM},
+    audio_source::{AudioSource, Clip, ClipSource},
+    env_var,
+    error::MiitopiaError,
+    media::{is_still, media_links, mime_from_path, Media, TempFile},
+    options::{AudioMix, OutputFormat, RenderOptions},
+    queue::{RenderQueue, Ticket},
+    settings::Settings,
+    slideshow::make_slideshow,
+};
+
+/// Compresses the first input whenever the second (the sidechain) is loud.
+const SIDECHAIN: &str = "sidechaincompress=threshold=0.03:ratio=8:attack=20:release=400";
+/// Mix two inputs at their original volume, as long as the first input.
+const AMIX: &str = "amix=inputs=2:duration=first:normalize=0";
+
+/// Check if the media file at `path` has an audio stream. ffprobe is killed if
+/// the returned future is dropped.
+async fn has_audio(path: &Path) -> bool {
+    let output = tokio::process::Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "a"])
+        .args(["-show_entries", "stream=index", "-of", "csv=p=0"])
+        .arg(path)
+        .kill_on_drop(true)
+        .output()
+        .await;
+
+    match output {
+        Ok(output) => output.status.success() && !output.stdout.trim_ascii().is_empty(),
+        Err(why) => {
+            log::warn!("Failed to run ffprobe: {why}");
+            false
+        }
+    }
+}
+
+/// How long a render can take before it's killed. Set with the
+/// `MIITOPIA_RENDER_TIMEOUT` env var, in seconds.
+fn render_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        env_var("MIITOPIA_RENDER_TIMEOUT")
+            .and_then(|secs| Duration::try_from_secs_f32(secs).ok())
+            .unwrap_or(Duration::from_secs(60))
+    })
+}
+
+/// When a render starting now has to be finished by, see [`render_timeout`].
+pub fn render_deadline() -> tokio::time::Instant {
+    tokio::time::Instant::now() + render_timeout()
+}
+
+/// The longest side a video can have before it's scaled down. Set with the
+/// `MIITOPIA_MAX_DIMENSION` env var, in pixels.
+fn max_dimension() -> u32 {
+    static MAX_DIMENSION: OnceLock<u32> = OnceLock::new();
+    *MAX_DIMENSION.get_or_init(|| {
+        env_var("MIITOPIA_MAX_DIMENSION")
+            .filter(|max| *max >= 2)
+            .unwrap_or(1280)
+    })
+}
+
+/// The size every video is letterboxed to, if there is one. Set with the
+/// `MIITOPIA_CANVAS` env var, like `1280x720`.
+fn canvas() -> Option<(u32, u32)> {
+    static CANVAS: OnceLock<Option<(u32, u32)>> = OnceLock::new();
+    *CANVAS.get_or_init(|| {
+        let value: String = env_var("MIITOPIA_CANVAS")?;
+        let canvas = value
+            .split_once('x')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .filter(|(width, height)| *width >= 2 && *height >= 2);
+        if canvas.is_none() {
+            log::warn!("Ignoring MIITOPIA_CANVAS, '{value}' is not like 1280x720");
+        }
+        canvas
+    })
+}
+
+/// Bitrate of the audio when we're trying to hit a size limit, in bits/s.
+const AUDIO_BITRATE: usize = 96_000;
+const AUDIO_BITRATE_STR: &str = "96k";
+
+/// Turns video into a gif with its own palette.
+const GIF_FILTER: &str =
+    "split[frames][copy];[copy]palettegen[palette];[frames][palette]paletteuse";
+/// A framerate gifs can manage, pictures keep their [`STILL_FRAMERATE`].
+const GIF_FRAMERATE: &str = "fps=15";
+
+/// Pictures don't change, so they're shown at a couple of frames a second
+/// with a keyframe every few seconds, instead of encoding the same frame over
+/// and over.
+const STILL_FRAMERATE: &str = "2";
+const STILL_KEYFRAME_INTERVAL: &str = "10";
+
+/// The font captions are written in. Captions are left off if it's missing.
+pub const CAPTION_FONT: &str = "./resources/fonts/DejaVuSans-Bold.ttf";
+/// How long the caption is shown for, and how long of that it spends fading
+/// out, in seconds.
+const CAPTION_LENGTH: f32 = 3.0;
+const CAPTION_FADE: f32 = 0.5;
+
+/// How much the video is scaled by on each attempt to fit the upload limit.
+const DOWNSCALE_STEPS: [f32; 4] = [1.0, 1.0, 0.75, 0.5];
+
+/// Encoder settings, changed between attempts to fit a render under the
+/// upload limit.
+struct Encode {
+    scale: f32,
+    /// Target video bitrate in bits/s, or ffmpeg's default quality if `None`.
+    video_bitrate: Option<usize>,
+}
+
+impl Encode {
+    /// The filters for the video, with the text in `caption` (a file) drawn
+    /// over it if there is one. `still` is if the video is a looped picture.
+    fn video_filter(&self, format: OutputFormat, caption: Option<&Path>, still: bool) -> String {
+        // gifs need a palette made for them, everything else is yuv420p.
+        let pixels = match format {
+            OutputFormat::Gif if still => GIF_FILTER.to_string(),
+            OutputFormat::Gif => format!("{GIF_FRAMERATE},{GIF_FILTER}"),
+            _ => "format=yuv420p".to_string(),
+        };
+        // Sizes must be even for yuv420p, encoders refuse odd ones.
+        let size = match canvas() {
+            // Fit inside the canvas and fill the rest with black bars.
+            Some((width, height)) => {
+                let width = even(width as f32 * self.scale);
+                let height = even(height as f32 * self.scale);
+                format!(
+                    "scale={width}:{height}:force_original_aspect_ratio=decrease:force_divisible_by=2,\
+                     pad={width}:{height}:(ow-iw)/2:(oh-ih)/2,setsar=1"
+                )
             }
+            // Keep the shape, but shrink anything too big to encode quickly.
+            None => format!(
+                "scale='min({0},iw)*{1}':'min({0},ih)*{1}':\
+                 force_original_aspect_ratio=decrease:force_divisible_by=2",
+                max_dimension(),
+                self.scale
+            ),
+        };
+        match caption {
+            // Drawn after scaling so the text is the same size on every video.
+            // Pictures only get a couple of frames a second, too few to fade.
+            Some(caption) => format!("{size},{},{pixels}", caption_filter(caption, !still)),
+            None => format!("{size},{pixels}"),
+        }
+    }
+
+    /// Settings for the next attempt after a render of `duration` seconds came
+    /// out `size` bytes, over the `limit`.
+    fn shrink(&self, size: usize, limit: usize, duration: f32, scale: f32) -> Encode {
+        let video_bitrate = match self.video_bitrate {
+            // Aim a little under the limit by however much we overshot.
+            Some(bitrate) => bitrate * limit / size * 9 / 10,
+            // Estimate the bitrate that fits from the duration, leaving room
+            // for the audio and container.
+            None => {
+                let total = (limit as f32 * 8.0 * 0.9 / duration.max(1.0)) as usize;
+                total.saturating_sub(AUDIO_BITRATE)
+            }
+        };
+        Encode {
+            scale,
+            video_bitrate: Some(video_bitrate.max(16_000)),
         }
     }
-    map
 }
+
+/// Draws the text in the file at `caption` along the bottom of the video for the
+/// first [`CAPTION_LENGTH`] seconds, fading out at the end if `fade`.
+fn caption_filter(caption: &Path, fade: bool) -> String {
+    // Only fade in the last moment, so it's readable until then.
+    let fade_start = CAPTION_LENGTH - CAPTION_FADE;
+    let alpha = match fade {
+        true => format!(":alpha='if(lt(t,{fade_start}),1,({CAPTION_LENGTH}-t)/{CAPTION_FADE})'"),
+        false => String::new(),
+    };
+    format!(
+        "drawtext=fontfile={CAPTION_FONT}:textfile={}:expansion=none:\
+         fontsize=h/12:fontcolor=white:borderw=3:bordercolor=black:\
+         x=(w-text_w)/2:y=h-text_h-h/12:enable='lt(t,{CAPTION_LENGTH})'{alpha}",
+        caption.display()
+    )
+}
+
+/// Write the title of the track in `clip` to a file for [`caption_filter`],
+/// if it should be shown.
+async fn caption_file(clip: &Clip, options: &RenderOptions) -> io::Result<Option<TempFile>> {
+    if !options.caption() || !options.format().has_video() {
+        return Ok(None);
+    }
+    let Some(title) = clip.title() else {
+        return Ok(None);
+    };
+    if tokio::fs::metadata(CAPTION_FONT).await.is_err() {
+        debug!("There's no font at {CAPTION_FONT}, leaving the caption off");
+        return Ok(None);
+    }
+    // ffmpeg reads the title from a file so it doesn't need escaping.
+    TempFile::with_extension(title.as_bytes(), "txt")
+        .await
+        .map(Some)
+}
+
+/// Round `size` down to an even number of pixels, at least 2.
+fn even(size: f32) -> u32 {
+    (size as u32 / 2 * 2).max(2)
+}
+
+/// Run an ffmpeg command, streaming `input` to its stdin (if there is any)
+/// while reading its output. Returns stdout and the trimmed stderr, if ffmpeg
+/// wrote anything there.
+///
+/// ffmpeg is killed if the returned future is dropped.
+pub async fn run_ffmpeg(
+    cmd: std::process::Command,
+    input: Option<&[u8]>,
+) -> Result<(Vec<u8>, Option<String>), MiitopiaError> {
+    let mut child = tokio::process::Command::from(cmd)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdin = child.stdin.take();
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+
+    let write = async {
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            stdin.write_all(input).await?;
+            // Dropping stdin closes it so ffmpeg knows the input is over.
+        }
+        Ok::<_, io::Error>(())
+    };
+    let read_stdout = async {
+        let mut buf = Vec::new();
+        if let Some(stdout) = &mut stdout {
+            stdout.read_to_end(&mut buf).await?;
+        }
+        Ok::<_, io::Error>(buf)
+    };
+    let read_stderr = async {
+        let mut buf = Vec::new();
+        if let Some(stderr) = &mut stderr {
+            stderr.read_to_end(&mut buf).await?;
+        }
+        Ok::<_, io::Error>(buf)
+    };
+
+    let (written, output, err_output) = tokio::join!(write, read_stdout, read_stderr);
+    let status = child.wait().await?;
+
+    // Create a string from the error of ffmpeg then trim the whitespace.
+    let err_str = String::from_utf8_lossy(&err_output?).trim().to_string();
+    if !status.success() {
+        return Err(MiitopiaError::Ffmpeg(err_str));
+    }
+
+    // ffmpeg can stop reading before it has everything (like when -shortest
+    // cuts a gif off), that's fine as long as it finished happily.
+    match written {
+        Err(why) if why.kind() != io::ErrorKind::BrokenPipe => return Err(why.into()),
+        _ => {}
+    }
+
+    let stderr = match err_str.is_empty() {
+        true => None,
+        false => Some(err_str),
+    };
+    Ok((output?, stderr))
+}
+
 pub struct JobResult {
-    pub audio_file: String,
-    pub attachment: Attachment,
+    pub clip: Clip,
+    /// Where the media came from. See [`Media::origin`].
+    pub source: String,
     pub stderr: Option<String>,
     pub output_file: Vec<u8>,
     pub job_time: Duration,
 }
 
+/// The filters for the soundtrack on its own. It's normalised so every
+/// soundtrack is about as loud, then faded in and out so it doesn't click.
+fn music_filter(clip: &Clip, options: &RenderOptions) -> String {
+    // Library tracks were measured when they were scanned, so the whole track
+    // gets the same gain. Anything else is normalised on the fly.
+    let loudnorm = match &clip.source {
+        ClipSource::Miitopia(track) => track.analysis.loudness.as_ref().map(Loudness::filter),
+        _ => None,
+    };
+    // loudnorm works at 192kHz, bring it back down.
+    let mut filters = vec![
+        loudnorm.unwrap_or_else(|| LOUDNORM.to_string()),
+        "aresample=48000".to_string(),
+    ];
+
+    let fade_in = options.fade_in().min(clip.duration);
+    if fade_in > 0.0 {
+        filters.push(format!("afade=t=in:st=0:d={fade_in}"));
+    }
+    let fade_out = options.fade_out().min(clip.duration);
+    if fade_out > 0.0 {
+        let start = clip.duration - fade_out;
+        filters.push(format!("afade=t=out:st={start}:d={fade_out}"));
+    }
+    filters.join(",")
+}
+
+/// Render `media` with the audio from `clip`, giving up at `deadline`.
 pub async fn apply_music(
-    audio_file: String,
-    start: f32,
-    duration: f32,
-    attachment: Attachment,
+    clip: Clip,
+    media: Media,
+    options: &RenderOptions,
+    deadline: tokio::time::Instant,
 ) -> Result<JobResult, MiitopiaError> {
     let start_time = Instant::now();
-    let duration_str = duration.to_string();
-    let start_str = start.to_string();
-    // Create our ffmpeg builder.
-    let ff_builder = FfmpegBuilder::new()
-        .option(Parameter::Single("hide_banner"))
-        // .option(Parameter::KeyValue("loglevel", "warning"))
-        .option(Parameter::KeyValue("loglevel", "error"))
-        .option(Parameter::Single("nostdin"))
-        .input(
-            File::new(audio_file.as_str())
-                .option(Parameter::KeyValue("ss", start_str.as_str()))
-                .option(Parameter::KeyValue("t", duration_str.as_str())),
-        );
+    let duration_str = clip.duration.to_string();
+    let start_str = clip.start.to_string();
 
-    // Get the mimetype of the attachment.
-    let mimetype = match &attachment.content_type {
-        Some(mimetype) => mimetype,
-        None => return Err(MiitopiaError::InvalidFileType),
+    // Only videos can have audio to mix with.
+    let mix = match media.mimetype.starts_with("video/") {
+        true => options.mix,
+        false => AudioMix::Replace,
     };
 
-    let mut shortest = true;
+    // Containers like mp4 can keep their index at the end of the file, so
+    // ffmpeg needs to seek. Those can't be piped in, write them to disk instead.
+    // Videos we're mixing also go on disk so we can probe them for audio.
+    // Pictures are looped from disk, with an extension so ffmpeg knows how to
+    // decode them.
+    let still = media.is_still();
+    let temp_file = match media.mimetype.as_str() {
+        mime if still => {
+            let extension = mime.strip_prefix("image/").unwrap_or_default();
+            Some(TempFile::with_extension(&media.bytes, extension).await?)
+        }
+        "video/mp4" | "video/quicktime" | "video/x-matroska" => {
+            Some(TempFile::create(&media.bytes).await?)
+        }
+        _ if mix != AudioMix::Replace => Some(TempFile::create(&media.bytes).await?),
+        _ => None,
+    };
+    let temp_path = match &temp_file {
+        Some(file) => file.path().to_string_lossy().to_string(),
+        None => String::new(),
+    };
 
-    // Depending on what kind of file we get, we need to do different things.
-    let mut ff_builder = match mimetype.as_str() {
-        "image/png" | "image/jpeg" | "image/webp" | "image/bmp" => {
-            shortest = false;
-            ff_builder.input(
-                File::new("-")
-                    .option(Parameter::KeyValue("f", "image2pipe"))
-                    .option(Parameter::KeyValue("framerate", "24")),
-            )
+    // Build the filter that mixes the soundtrack ([music]) with the original
+    // audio (1:a). The quieter one is ducked whenever the louder one plays.
+    // Probing counts towards the render's time limit too.
+    let original_audio = match (mix, &temp_file) {
+        (AudioMix::Replace, _) | (_, None) => false,
+        (_, Some(file)) => match timeout_at(deadline, has_audio(file.path())).await {
+            Ok(has_audio) => has_audio,
+            Err(_) => {
+                log::warn!("Probing {} took too long, killed ffprobe", media.origin());
+                return Err(MiitopiaError::Timeout(render_timeout()));
+            }
+        },
+    };
+    let mix_filter = match mix {
+        AudioMix::Replace => None,
+        _ if !original_audio => {
+            debug!("{} has no audio to mix, replacing it", media.origin());
+            None
         }
-        "image/gif" => ff_builder.input(
-            File::new("-")
-                .option(Parameter::KeyValue("f", "gif"))
-                .option(Parameter::KeyValue("stream_loop", "-1")),
-        ),
-        "video/webm" => ff_builder.input(File::new("-").option(Parameter::KeyValue("f", "webm"))),
-        mime => return Err(MiitopiaError::UnsupportedFileType(mime.to_string())),
+        AudioMix::Voice => Some(format!(
+            "[1:a]asplit=2[orig][sc];[music][sc]{SIDECHAIN}[ducked];[ducked][orig]{AMIX}[aout]"
+        )),
+        AudioMix::Music => Some(format!(
+            "[music]asplit=2[loud][sc];[1:a][sc]{SIDECHAIN}[ducked];[loud][ducked]{AMIX}[aout]"
+        )),
+    };
+    let format = options.format();
+    let filter = match &mix_filter {
+        Some(mix_filter) => format!("[0:a]{}[music];{mix_filter}", music_filter(&clip, options)),
+        None => format!("[0:a]{}[music]", music_filter(&clip, options)),
     };
 
-    // Create our output
-    let mut output = File::new("-")
-        .option(Parameter::KeyValue("f", "webm"))
-        .option(Parameter::KeyValue("vf", "format=yuv420p"))
-        .option(Parameter::KeyValue("map", "0:a:0"))
-        .option(Parameter::KeyValue("map", "1:v:0"))
-        .option(Parameter::KeyValue("threads", "4"));
+    // Only still images need to be told when to stop.
+    let shortest = !still;
 
-    if shortest {
-        output = output.option(Parameter::Single("shortest"));
-    }
+    // Use the mixed audio if we're mixing, otherwise just the soundtrack.
+    let audio_map = match &mix_filter {
+        Some(_) => "[aout]",
+        None => "[music]",
+    };
 
-    ff_builder = ff_builder
-        .output(output)
-        .stdout(Stdio::piped())
-        .stdin(Stdio::piped())
-        .stderr(Stdio::piped());
+    let source = media.origin().to_string();
+    let source_bytes = media.bytes;
+    let caption = caption_file(&clip, options).await?;
 
-    // Download our source file.
-    let source_bytes = attachment.download().await?;
+    // Start with ffmpeg's defaults. If that's too big to upload, aim for a
+    // bitrate that fits and shrink the video until it does.
+    let mut encode = Encode {
+        scale: 1.0,
+        video_bitrate: None,
+    };
+    let mut attempt = 0;
+    let (output, stderr) = loop {
+        let vf = encode.video_filter(format, caption.as_ref().map(TempFile::path), still);
+        let bitrate = encode.video_bitrate.map(|bitrate| bitrate.to_string());
+
+        // Create our ffmpeg builder.
+        let mut ff_builder = FfmpegBuilder::new()
+            .option(Parameter::Single("hide_banner"))
+            // .option(Parameter::KeyValue("loglevel", "warning"))
+            .option(Parameter::KeyValue("loglevel", "error"))
+            .option(Parameter::Single("nostdin"))
+            .input(
+                File::new(clip.audio.as_str())
+                    .option(Parameter::KeyValue("ss", start_str.as_str()))
+                    .option(Parameter::KeyValue("t", duration_str.as_str())),
+            );
+
+        // Depending on what kind of file we get, we need to do different things.
+        ff_builder = match media.mimetype.as_str() {
+            // Show the one frame until the clip is over.
+            _ if still => ff_builder.input(
+                File::new(temp_path.as_str())
+                    .option(Parameter::KeyValue("loop", "1"))
+                    .option(Parameter::KeyValue("framerate", STILL_FRAMERATE)),
+            ),
+            // Anything else we've written to disk can be read straight from there.
+            _ if temp_file.is_some() => ff_builder.input(File::new(temp_path.as_str())),
+            "image/gif" => ff_builder.input(
+                File::new("-")
+                    .option(Parameter::KeyValue("f", "gif"))
+                    .option(Parameter::KeyValue("stream_loop", "-1")),
+            ),
+            "video/webm" => {
+                ff_builder.input(File::new("-").option(Parameter::KeyValue("f", "webm")))
+            }
+            mime => return Err(MiitopiaError::UnsupportedFileType(mime.to_string())),
+        };
+
+        // Create our output, the muxers are named after their extensions.
+        // Stop at the end of the clip even if the video keeps going, gifs
+        // loop forever.
+        let mut output = File::new("-")
+            .option(Parameter::KeyValue("f", format.extension()))
+            .option(Parameter::KeyValue("t", duration_str.as_str()))
+            .option(Parameter::KeyValue("threads", "4"));
+
+        if format.has_audio() {
+            ff_builder = ff_builder.option(Parameter::KeyValue("filter_complex", &filter));
+            output = output.option(Parameter::KeyValue("map", audio_map));
+        }
+        if format.has_video() {
+            output = output
+                .option(Parameter::KeyValue("vf", vf.as_str()))
+                .option(Parameter::KeyValue("map", "1:v:0"));
+        }
 
-    // Start ffmpeg.
-    let mut cmd = ff_builder.to_command();
+        output = match format {
+            OutputFormat::Webm | OutputFormat::Gif => output,
+            // mp4 normally goes back to write its index at the start, which
+            // can't be done to a pipe.
+            OutputFormat::Mp4 => output
+                .option(Parameter::KeyValue("c:v", "libx264"))
+                .option(Parameter::KeyValue("c:a", "aac"))
+                .option(Parameter::KeyValue("movflags", "frag_keyframe+empty_moov")),
+            OutputFormat::Ogg => output.option(Parameter::KeyValue("c:a", "libopus")),
+        };
+
+        if still && format.has_video() {
+            output = output.option(Parameter::KeyValue("g", STILL_KEYFRAME_INTERVAL));
+            if format == OutputFormat::Mp4 {
+                output = output.option(Parameter::KeyValue("tune", "stillimage"));
+            }
+        }
 
-    // TODO: Only run this if env_logger is logging debug messages.
-    // Get all the args from ffmpeg and join them together into one single string.
-    let mut arg_str = String::new();
-    for arg in cmd.get_args() {
-        arg_str.push(' ');
-        arg_str.push_str(arg.to_str().unwrap_or_default());
-    }
-    debug!("{}{}", cmd.get_program().to_str().unwrap(), arg_str);
-
-    // Start ffmpeg.
-    let mut child = cmd.spawn()?;
-
-    // Take stdin and write downloaded file in another thread.
-    let mut stdin = child.stdin.take().expect("Failed to get stdin");
-    std::thread::spawn(move || {
-        stdin
-            .write_all(&source_bytes)
-            .expect("Failed to write to stdin");
-    });
-
-    // Read our ffmpeg output.
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        // Create a string from the error of ffmpeg then trim the whitespace.
-        let err_str = String::from_utf8_lossy(&output.stderr);
-        let err_str = err_str.trim();
-
-        return Err(MiitopiaError::Ffmpeg(err_str.to_string()));
-    }
+        if let Some(bitrate) = &bitrate {
+            if format.has_video() {
+                output = output
+                    .option(Parameter::KeyValue("b:v", bitrate.as_str()))
+                    .option(Parameter::KeyValue("maxrate", bitrate.as_str()))
+                    .option(Parameter::KeyValue("bufsize", bitrate.as_str()));
+            }
+            if format.has_audio() {
+                output = output.option(Parameter::KeyValue("b:a", AUDIO_BITRATE_STR));
+            }
+        }
 
-    let mut stderr = String::from_utf8(output.stderr).ok();
-    if stderr.is_some() {
-        let str = stderr.unwrap();
-        let str = str.trim();
-        if str.is_empty() {
-            stderr = None;
-        } else {
-            stderr = Some(str.to_string())
+        if shortest {
+            output = output.option(Parameter::Single("shortest"));
         }
-    }
+
+        ff_builder = ff_builder
+            .output(output)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        // Pipe the source in if it isn't on disk.
+        if temp_file.is_none() {
+            ff_builder = ff_builder.stdin(Stdio::piped());
+        }
+
+        // Start ffmpeg.
+        let cmd = ff_builder.to_command();
+
+        // TODO: Only run this if env_logger is logging debug messages.
+        // Get all the args from ffmpeg and join them together into one single string.
+        let mut arg_str = String::new();
+        for arg in cmd.get_args() {
+            arg_str.push(' ');
+            arg_str.push_str(arg.to_str().unwrap_or_default());
+        }
+        debug!("{}{}", cmd.get_program().to_str().unwrap(), arg_str);
+
+        // Run ffmpeg, giving up (and killing ffmpeg) if it takes too long.
+        let input = temp_file.is_none().then_some(source_bytes.as_slice());
+        let (output, stderr) = match timeout_at(deadline, run_ffmpeg(cmd, input)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                log::warn!("Rendering {} took too long, killed ffmpeg", source);
+                return Err(MiitopiaError::Timeout(render_timeout()));
+            }
+        };
+
+        // Done if it fits.
+        let size = output.len();
+        let limit = match options.max_size {
+            Some(limit) if size > limit => limit,
+            _ => break (output, stderr),
+        };
+
+        attempt += 1;
+        // Another go at the same size wouldn't come out any smaller.
+        if !format.has_video_bitrate() {
+            while DOWNSCALE_STEPS
+                .get(attempt)
+                .is_some_and(|scale| *scale >= encode.scale)
+            {
+                attempt += 1;
+            }
+        }
+        if attempt >= DOWNSCALE_STEPS.len() {
+            return Err(MiitopiaError::TooLarge(size, limit));
+        }
+
+        debug!(
+            "{} is {} over the {} limit, re-encoding",
+            source,
+            (size - limit).human_count_bytes(),
+            limit.human_count_bytes()
+        );
+        encode = encode.shrink(size, limit, clip.duration, DOWNSCALE_STEPS[attempt]);
+    };
 
     Ok(JobResult {
         job_time: start_time.elapsed(),
-        attachment,
-        audio_file,
-        output_file: output.stdout,
-        stderr: stderr,
+        source,
+        clip,
+        output_file: output,
+        stderr,
     })
 }
 
-pub async fn process_message(ctx: &Context, msg: &Message) -> Result<(), Vec<MiitopiaError>> {
-    let _ = ctx.http().broadcast_typing(msg.channel_id.0);
+/// The biggest file (in bytes) we can send in reply to `msg`. Boosted
+/// servers let us upload bigger files.
+fn upload_limit(ctx: &Context, msg: &Message) -> usize {
+    let tier = msg
+        .guild_id
+        .and_then(|id| ctx.cache.guild_field(id, |guild| guild.premium_tier));
+
+    match tier {
+        Some(PremiumTier::Tier2) => 50 * 1024 * 1024,
+        Some(PremiumTier::Tier3) => 100 * 1024 * 1024,
+        _ => 10 * 1024 * 1024,
+    }
+}
+
+/// Media found in a discord message that hasn't been downloaded yet.
+enum MediaInput {
+    Attachment(Attachment),
+    /// The url of an image or gif linked in the message.
+    Url(String),
+}
+
+impl MediaInput {
+    async fn download(&self) -> Result<Media, MiitopiaError> {
+        match self {
+            MediaInput::Attachment(attachment) => Media::from_attachment(attachment).await,
+            MediaInput::Url(url) => Media::from_url(url).await,
+        }
+    }
 
+    /// If this looks like a single picture. Links are judged by their
+    /// extension, so this can be wrong until it's downloaded.
+    fn is_still(&self) -> bool {
+        match self {
+            MediaInput::Attachment(attachment) => {
+                attachment.content_type.as_deref().is_some_and(is_still)
+            }
+            MediaInput::Url(url) => reqwest::Url::parse(url)
+                .ok()
+                .and_then(|url| mime_from_path(Path::new(url.path())))
+                .is_some_and(is_still),
+        }
+    }
+}
+
+/// Wait for our turn in the queue, then download `inputs` and apply music to
+/// them. More than one input is made into a slideshow first.
+async fn render_inputs(
+    ticket: Ticket,
+    inputs: Vec<MediaInput>,
+    clip: Clip,
+    options: &RenderOptions,
+) -> Result<JobResult, MiitopiaError> {
+    let _permit = ticket.wait().await?;
+    let mut media = futures::future::try_join_all(inputs.iter().map(MediaInput::download)).await?;
+    // Making the slideshow and adding the music share one time limit.
+    let deadline = render_deadline();
+    if media.len() == 1 {
+        return apply_music(clip, media.remove(0), options, deadline).await;
+    }
+
+    if let Some(media) = media.iter().find(|media| !media.is_still()) {
+        return Err(MiitopiaError::UnsupportedFileType(media.mimetype.clone()));
+    }
+    let slideshow = make_slideshow(&media, clip.duration);
+    let slideshow = match timeout_at(deadline, slideshow).await {
+        Ok(slideshow) => slideshow?,
+        Err(_) => {
+            log::warn!(
+                "Making a slideshow of {} pictures took too long",
+                media.len()
+            );
+            return Err(MiitopiaError::Timeout(render_timeout()));
+        }
+    };
+    apply_music(clip, slideshow, options, deadline).await
+}
+
+pub async fn process_message(ctx: &Context, msg: &Message) -> Result<(), Vec<MiitopiaError>> {
     let typing = match msg.channel_id.start_typing(&ctx.http) {
         Ok(typing) => Some(typing),
         Err(reason) => {
@@ -209,22 +675,104 @@ pub async fn process_message(ctx: &Context, msg: &Message) -> Result<(), Vec<Mii
     let mut rng = SmallRng::from_entropy();
 
     // Get the content of the discord message.
-    let msg_content = &msg.content_safe(&ctx.cache);
+    let mut msg_content = msg.content_safe(&ctx.cache);
+
+    // Use the attachments, or any images and gifs linked in the message if
+    // there are none.
+    let inputs: Vec<MediaInput> = if msg.attachments.is_empty() {
+        let mut inputs: Vec<MediaInput> = msg
+            .embeds
+            .iter()
+            .filter_map(|embed| {
+                let media_url = Media::embed_url(embed)?;
+                // Don't mistake the link to the image for a link to audio.
+                if let Some(url) = &embed.url {
+                    msg_content = msg_content.replace(url.as_str(), "");
+                }
+                Some(MediaInput::Url(media_url.to_string()))
+            })
+            .collect();
+        // Discord usually hasn't embedded the links yet, go by what they end
+        // in instead.
+        if inputs.is_empty() {
+            let links: Vec<String> = media_links(&msg_content)
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+            for link in links {
+                msg_content = msg_content.replace(&link, "");
+                inputs.push(MediaInput::Url(link));
+            }
+        }
+        inputs
+    } else {
+        msg.attachments
+            .iter()
+            .cloned()
+            .map(MediaInput::Attachment)
+            .collect()
+    };
+
+    let (mut options, query) = RenderOptions::from_msg_content(&msg_content);
 
     // Find out where our audio is coming from. Url, Spotify or Miitopia?
-    let source = AudioSource::from_msg_content(&msg_content);
+    let source = AudioSource::from_msg_content(&msg_content, &query);
     log::trace!("Using {} AudioSource", source);
 
+    options.max_size = Some(upload_limit(ctx, msg));
+    if let Some(guild) = msg.guild_id {
+        let data = ctx.data.read().await;
+        if let Some(settings) = data.get::<Settings>() {
+            settings.apply(guild.0, &mut options);
+        }
+    }
+
     let mut errors: Vec<MiitopiaError> = vec![];
 
+    let queue = ctx
+        .data
+        .read()
+        .await
+        .get::<RenderQueue>()
+        .expect("Expected RenderQueue in TypeMap")
+        .clone();
+
+    // Each job is rendered with its own track. In a slideshow all the pictures
+    // share one, anything else still gets its own.
+    let mut jobs: Vec<Vec<MediaInput>> = vec![];
+    let mut slides = vec![];
+    for input in inputs {
+        match options.slideshow && input.is_still() {
+            true => slides.push(input),
+            false => jobs.push(vec![input]),
+        }
+    }
+    match slides.len() {
+        0 => {}
+        1 => jobs.extend(slides.into_iter().map(|slide| vec![slide])),
+        _ => jobs.insert(0, slides),
+    }
+
     // Start processing the attachments.
     let mut raw_futures = Vec::new();
-    for attachment in msg.attachments.clone() {
-        let track = source.get_track(&ctx.data, &mut rng).await;
-        match track {
-            Ok((path, start)) => {
-                raw_futures.push(apply_music(path, start, MAX_AUDIO_LENGTH, attachment))
+    let mut position = None;
+    for inputs in jobs {
+        // Get in line before doing anything else.
+        let ticket = match queue.join(msg.guild_id.map(|id| id.0).unwrap_or_default()) {
+            Some(ticket) => ticket,
+            // The rest won't fit either, one error is enough.
+            None => {
+                errors.push(MiitopiaError::QueueFull);
+                break;
             }
+        };
+        position = position.or(ticket.position());
+
+        let track = source
+            .get_track(&ctx.data, &mut rng, &options, msg.channel_id.0)
+            .await;
+        match track {
+            Ok(clip) => raw_futures.push(render_inputs(ticket, inputs, clip, &options)),
             Err(err) => {
                 log::error!("Failed to get track: {:?} for {}", err, msg.id);
                 errors.push(err);
@@ -232,6 +780,16 @@ pub async fn process_message(ctx: &Context, msg: &Message) -> Result<(), Vec<Mii
         }
     }
 
+    // Let them know why it's taking a while.
+    if let Some(position) = position {
+        if let Err(why) = msg
+            .reply(&ctx.http, format!("You are #{position} in the queue."))
+            .await
+        {
+            log::warn!("Failed to send queue position: {:?}", why);
+        }
+    }
+
     let unpin_futures: Vec<_> = raw_futures.into_iter().map(Box::pin).collect();
     let mut futures = unpin_futures;
 
@@ -243,10 +801,10 @@ pub async fn process_message(ctx: &Context, msg: &Message) -> Result<(), Vec<Mii
                 // TODO: Don't print this (clone stderr!!) if env_logger isn't logging info.
                 log::info!(
                     "Processed {}\n\tSize: {}\n\tTime: {}\n\tTrack: {}\n\tffmpeg stderr: {}",
-                    job.attachment.url,
+                    job.source,
                     job.output_file.len().human_count_bytes(),
                     job.job_time.human_duration(),
-                    job.audio_file,
+                    job.clip,
                     job.stderr.clone().unwrap_or("empty".to_string())
                 );
                 if let Err(why) = msg
@@ -254,7 +812,11 @@ pub async fn process_message(ctx: &Context, msg: &Message) -> Result<(), Vec<Mii
                     .send_message(&ctx.http, |m| {
                         m.add_file(AttachmentType::Bytes {
                             data: Cow::from(job.output_file),
-                            filename: "miitopia.webm".to_string(),
+                            filename: format!("miitopia.{}", options.format().extension()),
+                        })
+                        .embed(|em| {
+                            job.clip.embed(em);
+                            em
                         })
                     })
                     .await
@@ -279,7 +841,7 @@ pub async fn process_message(ctx: &Context, msg: &Message) -> Result<(), Vec<Mii
     }
 
     // Return the errors if there's errors.
-    if errors.len() > 0 {
+    if !errors.is_empty() {
         Err(errors)
     } else {
         Ok(())
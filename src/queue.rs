@@ -0,0 +1,225 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use indexmap::IndexMap;
+use log::debug;
+use serenity::prelude::TypeMapKey;
+use tokio::sync::oneshot;
+
+use crate::{env_var, error::MiitopiaError};
+
+/// Limits how many renders run at once. Jobs that have to wait are handed out
+/// round-robin between guilds, so one busy server can't starve the others.
+#[derive(Clone)]
+pub struct RenderQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// How many renders can run at once.
+    workers: usize,
+    /// How many renders can be waiting before we turn new ones away.
+    max_depth: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    /// Jobs waiting for a worker, grouped by guild. The guild at the front
+    /// goes next.
+    waiting: IndexMap<u64, VecDeque<oneshot::Sender<Permit>>>,
+}
+
+impl State {
+    fn queued(&self) -> usize {
+        self.waiting.values().map(VecDeque::len).sum()
+    }
+}
+
+/// A place in the [`RenderQueue`].
+pub enum Ticket {
+    /// A worker is free, start rendering.
+    Ready(Permit),
+    /// Waiting for a worker with this many jobs (including this one) in line.
+    Waiting(usize, oneshot::Receiver<Permit>),
+}
+
+/// Lets a render run. The worker is freed up when this is dropped.
+pub struct Permit {
+    queue: Option<RenderQueue>,
+}
+
+impl RenderQueue {
+    pub fn new(workers: usize, max_depth: usize) -> RenderQueue {
+        RenderQueue {
+            inner: Arc::new(Inner {
+                workers: workers.max(1),
+                max_depth,
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    /// Read the queue settings from the `MIITOPIA_WORKERS` and
+    /// `MIITOPIA_QUEUE_DEPTH` env vars.
+    pub fn from_env() -> RenderQueue {
+        let workers = env_var("MIITOPIA_WORKERS").unwrap_or(2);
+        let max_depth = env_var("MIITOPIA_QUEUE_DEPTH").unwrap_or(20);
+        RenderQueue::new(workers, max_depth)
+    }
+
+    /// Get in line to render something for `guild`. Use `0` for anything that
+    /// isn't in a guild. Returns `None` if the queue is full.
+    pub fn join(&self, guild: u64) -> Option<Ticket> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.running < self.inner.workers {
+            state.running += 1;
+            return Some(Ticket::Ready(Permit {
+                queue: Some(self.clone()),
+            }));
+        }
+
+        // Forget about anyone that gave up waiting.
+        state.waiting.retain(|_, senders| {
+            senders.retain(|sender| !sender.is_closed());
+            !senders.is_empty()
+        });
+
+        let queued = state.queued();
+        if queued >= self.inner.max_depth {
+            return None;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.entry(guild).or_default().push_back(sender);
+        debug!("Queued a render for guild {guild}, {} waiting", queued + 1);
+        Some(Ticket::Waiting(queued + 1, receiver))
+    }
+
+    /// Give a finished job's worker to the next guild in line.
+    fn release(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        while let Some((guild, mut senders)) = state.waiting.shift_remove_index(0) {
+            let sender = senders.pop_front();
+
+            // Send the guild to the back of the line if it has more jobs.
+            if !senders.is_empty() {
+                state.waiting.insert(guild, senders);
+            }
+
+            if let Some(sender) = sender {
+                let permit = Permit {
+                    queue: Some(self.clone()),
+                };
+                match sender.send(permit) {
+                    // The worker now belongs to the next job.
+                    Ok(()) => return,
+                    // They stopped waiting, don't let the permit release again.
+                    Err(mut permit) => permit.queue = None,
+                }
+            }
+        }
+
+        state.running -= 1;
+    }
+}
+
+impl Ticket {
+    /// Where we are in line, or `None` if we can start right away.
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            Ticket::Ready(_) => None,
+            Ticket::Waiting(position, _) => Some(*position),
+        }
+    }
+
+    /// Wait until a worker is free.
+    pub async fn wait(self) -> Result<Permit, MiitopiaError> {
+        match self {
+            Ticket::Ready(permit) => Ok(permit),
+            Ticket::Waiting(_, receiver) => receiver.await.map_err(|_| MiitopiaError::QueueFull),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release();
+        }
+    }
+}
+
+impl TypeMapKey for RenderQueue {
+    type Value = RenderQueue;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receiver(ticket: Option<Ticket>) -> oneshot::Receiver<Permit> {
+        match ticket {
+            Some(Ticket::Waiting(_, receiver)) => receiver,
+            _ => panic!("expected to wait"),
+        }
+    }
+
+    fn ready(ticket: Option<Ticket>) -> Permit {
+        match ticket {
+            Some(Ticket::Ready(permit)) => permit,
+            _ => panic!("expected to start right away"),
+        }
+    }
+
+    #[test]
+    fn turns_jobs_away_when_full() {
+        let queue = RenderQueue::new(1, 2);
+        let _running = ready(queue.join(1));
+        let first = queue.join(1);
+        let second = queue.join(2);
+        assert_eq!(first.as_ref().and_then(Ticket::position), Some(1));
+        assert_eq!(second.as_ref().and_then(Ticket::position), Some(2));
+        assert!(queue.join(3).is_none());
+    }
+
+    #[test]
+    fn takes_turns_between_guilds() {
+        let queue = RenderQueue::new(1, 10);
+        let running = ready(queue.join(1));
+        let mut first = receiver(queue.join(1));
+        let mut second = receiver(queue.join(1));
+        let mut other = receiver(queue.join(2));
+
+        drop(running);
+        let running = first.try_recv().expect("guild 1 goes first");
+        assert!(other.try_recv().is_err());
+
+        // Guild 2 gets a go before guild 1's second job.
+        drop(running);
+        let running = other.try_recv().expect("guild 2 goes next");
+        assert!(second.try_recv().is_err());
+
+        drop(running);
+        assert!(second.try_recv().is_ok());
+    }
+
+    #[test]
+    fn cancelled_jobs_free_their_worker() {
+        let queue = RenderQueue::new(1, 10);
+        let running = ready(queue.join(1));
+        drop(receiver(queue.join(2)));
+
+        // Nobody is left waiting, so the worker is free again.
+        drop(running);
+        assert_eq!(queue.inner.state.lock().unwrap().running, 0);
+        let _running = ready(queue.join(1));
+        let waiting = queue.join(1);
+        assert_eq!(waiting.as_ref().and_then(Ticket::position), Some(1));
+    }
+}
@@ -0,0 +1,59 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::Deserialize;
+use serenity::prelude::TypeMapKey;
+
+use crate::{
+    load_or_default,
+    options::{OutputFormat, RenderOptions},
+};
+
+/// Where the per guild settings are kept, written by hand.
+const SETTINGS_PATH: &str = "./resources/guilds.toml";
+
+/// Defaults for each guild, used when a message doesn't say otherwise:
+///
+/// ```toml
+/// [guilds.123456789012345678]
+/// format = "mp4"
+/// caption = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Settings {
+    /// Keyed by guild id.
+    #[serde(default)]
+    guilds: HashMap<String, GuildSettings>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct GuildSettings {
+    format: Option<OutputFormat>,
+    caption: Option<bool>,
+}
+
+impl Settings {
+    /// Load the settings, or use the defaults everywhere if there aren't any.
+    pub async fn load() -> Settings {
+        load_or_default(SETTINGS_PATH, "guild settings", toml::from_str).await
+    }
+
+    /// If any guild shows captions unless a message says otherwise.
+    pub fn any_captions(&self) -> bool {
+        self.guilds
+            .values()
+            .any(|settings| settings.caption == Some(true))
+    }
+
+    /// Fill in anything `options` doesn't set with the defaults for `guild`.
+    pub fn apply(&self, guild: u64, options: &mut RenderOptions) {
+        let Some(settings) = self.guilds.get(&guild.to_string()) else {
+            return;
+        };
+        options.format = options.format.or(settings.format);
+        options.caption = options.caption.or(settings.caption);
+    }
+}
+
+impl TypeMapKey for Settings {
+    type Value = Arc<Settings>;
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use indexmap::IndexMap;
+use log::warn;
+use rand::rngs::SmallRng;
+use serenity::prelude::TypeMapKey;
+use tokio::sync::Mutex;
+
+use crate::{
+    library::{pick_weighted, Track},
+    load_or_default,
+};
+
+/// Where we keep what's been played between restarts.
+const HISTORY_PATH: &str = "./resources/history.json";
+
+/// Remembers which tracks have been played in each channel, so random picks
+/// go through the whole library before anything repeats.
+pub struct ShuffleHistory {
+    /// The tracks played in each channel since it last heard everything,
+    /// oldest first.
+    played: Mutex<HashMap<u64, Vec<PathBuf>>>,
+}
+
+impl ShuffleHistory {
+    /// Load the history saved by the last run, or start fresh.
+    pub async fn load() -> ShuffleHistory {
+        let played = load_or_default(HISTORY_PATH, "shuffle history", |json| {
+            serde_json::from_str(json)
+        })
+        .await;
+        ShuffleHistory {
+            played: Mutex::new(played),
+        }
+    }
+
+    /// Pick a random track from `pool` that hasn't been played in `channel`
+    /// yet. Returns its index in `tracks`. `pool` must not be empty.
+    pub async fn pick(
+        &self,
+        channel: u64,
+        tracks: &IndexMap<PathBuf, Track>,
+        pool: &[usize],
+        rng: &mut SmallRng,
+    ) -> usize {
+        let mut played = self.played.lock().await;
+        pick_unplayed(played.entry(channel).or_default(), tracks, pool, rng)
+    }
+
+    /// Remember that `path` was played in `channel`.
+    pub async fn played(&self, channel: u64, path: &Path) {
+        let mut played = self.played.lock().await;
+        record(played.entry(channel).or_default(), path);
+
+        if let Err(why) = save(&played).await {
+            warn!("Failed to save the shuffle history: {}", why);
+        }
+    }
+}
+
+/// Pick a random track from `pool` that isn't in `history`, starting the pool
+/// over once everything in it has been heard. Returns its index in `tracks`.
+fn pick_unplayed(
+    history: &mut Vec<PathBuf>,
+    tracks: &IndexMap<PathBuf, Track>,
+    pool: &[usize],
+    rng: &mut SmallRng,
+) -> usize {
+    // Forget tracks that have left the library.
+    history.retain(|path| tracks.contains_key(path));
+
+    let in_pool = |path: &PathBuf| {
+        tracks
+            .get_index_of(path)
+            .is_some_and(|index| pool.contains(&index))
+    };
+    let unplayed = |history: &Vec<PathBuf>| -> Vec<usize> {
+        let heard: HashSet<&PathBuf> = history.iter().collect();
+        pool.iter()
+            .copied()
+            .filter(|index| !heard.contains(tracks.get_index(*index).unwrap().0))
+            .collect()
+    };
+
+    let mut candidates = unplayed(history);
+    if candidates.is_empty() {
+        // Heard everything in the pool, start it again. Just not with the
+        // last one, so it can't play twice in a row.
+        let last = history.last().cloned();
+        history.retain(|path| !in_pool(path));
+        candidates = unplayed(history);
+        candidates.retain(|index| tracks.get_index(*index).map(|(path, _)| path) != last.as_ref());
+        if candidates.is_empty() {
+            candidates = pool.to_vec();
+        }
+    }
+    pick_weighted(tracks, &candidates, rng)
+}
+
+/// Add `path` to the end of `history`, moving it there if it was already in
+/// it.
+fn record(history: &mut Vec<PathBuf>, path: &Path) {
+    history.retain(|played| played != path);
+    history.push(path.to_path_buf());
+}
+
+async fn save(played: &HashMap<u64, Vec<PathBuf>>) -> io::Result<()> {
+    let json = serde_json::to_vec(played)?;
+    tokio::fs::write(HISTORY_PATH, json).await
+}
+
+impl TypeMapKey for ShuffleHistory {
+    type Value = Arc<ShuffleHistory>;
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use crate::analysis::Analysis;
+
+    use super::*;
+
+    fn library(count: usize) -> IndexMap<PathBuf, Track> {
+        (0..count)
+            .map(|number| {
+                let path = PathBuf::from(format!("{number}.ogg"));
+                let track = Track {
+                    path: path.clone(),
+                    duration: 60.0,
+                    title: None,
+                    number: None,
+                    album: None,
+                    artist: None,
+                    composer: None,
+                    weight: 1.0,
+                    tags: vec![],
+                    analysis: Analysis::default(),
+                };
+                (path, track)
+            })
+            .collect()
+    }
+
+    /// Pick and play `count` tracks, returning their indexes in order.
+    fn play(
+        history: &mut Vec<PathBuf>,
+        tracks: &IndexMap<PathBuf, Track>,
+        count: usize,
+        rng: &mut SmallRng,
+    ) -> Vec<usize> {
+        let pool: Vec<usize> = (0..tracks.len()).collect();
+        (0..count)
+            .map(|_| {
+                let index = pick_unplayed(history, tracks, &pool, rng);
+                record(history, &tracks.get_index(index).unwrap().1.path);
+                index
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plays_everything_before_repeating() {
+        let tracks = library(10);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut history = vec![];
+        for _ in 0..5 {
+            let mut played = play(&mut history, &tracks, 10, &mut rng);
+            played.sort();
+            assert_eq!(played, (0..10).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn never_repeats_across_cycles() {
+        let tracks = library(3);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut history = vec![];
+        let played = play(&mut history, &tracks, 300, &mut rng);
+        for pair in played.windows(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn forgets_tracks_that_left_the_library() {
+        let mut tracks = library(4);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut history = vec![];
+        play(&mut history, &tracks, 3, &mut rng);
+
+        // Whatever's left is picked, even if the last one played is gone.
+        let last = history.last().unwrap().clone();
+        tracks.shift_remove(&last);
+        let unplayed = play(&mut history, &tracks, 1, &mut rng)[0];
+        let (path, _) = tracks.get_index(unplayed).unwrap();
+        assert!(!history[..history.len() - 1].contains(path));
+        assert!(!history.contains(&last));
+    }
+}
@@ -0,0 +1,110 @@
+use std::process::Stdio;
+
+use ffmpeg_cli::{FfmpegBuilder, File, Parameter};
+use log::debug;
+
+use crate::{
+    error::MiitopiaError,
+    media::{Media, TempFile},
+    processor::run_ffmpeg,
+};
+
+/// The size every picture is fitted into. Anything a different shape gets
+/// black bars.
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 720;
+
+const FRAMERATE: &str = "24";
+
+/// The longest each picture takes to fade into the next, in seconds.
+const CROSSFADE: f32 = 0.5;
+
+/// Join still `images` into one silent video `duration` seconds long. Each
+/// picture gets an equal share of it and fades into the next.
+pub async fn make_slideshow(images: &[Media], duration: f32) -> Result<Media, MiitopiaError> {
+    let count = images.len().max(1) as f32;
+    // Fades overlap two pictures, so each is shown for a bit longer than its
+    // share. Short clips get quicker fades so every picture is seen on its own.
+    let fade = CROSSFADE.min(duration / count / 2.0);
+    let shown = (duration + fade * (count - 1.0)) / count;
+    let shown_str = shown.to_string();
+    let duration_str = duration.to_string();
+
+    // Looping a picture needs it on disk, with an extension so ffmpeg knows
+    // how to decode it.
+    let mut files = Vec::with_capacity(images.len());
+    for image in images {
+        let extension = image.mimetype.strip_prefix("image/").unwrap_or_default();
+        files.push(TempFile::with_extension(&image.bytes, extension).await?);
+    }
+    let paths: Vec<String> = files
+        .iter()
+        .map(|file| file.path().to_string_lossy().to_string())
+        .collect();
+
+    let (filter, map) = slideshow_filter(images.len(), shown, fade);
+
+    let mut ff_builder = FfmpegBuilder::new()
+        .option(Parameter::Single("hide_banner"))
+        .option(Parameter::KeyValue("loglevel", "error"))
+        .option(Parameter::Single("nostdin"));
+    for path in &paths {
+        ff_builder = ff_builder.input(
+            File::new(path)
+                .option(Parameter::KeyValue("loop", "1"))
+                .option(Parameter::KeyValue("framerate", FRAMERATE))
+                .option(Parameter::KeyValue("t", shown_str.as_str())),
+        );
+    }
+
+    // This only gets encoded again when the music is added, so make it fast
+    // and nearly lossless.
+    let cmd = ff_builder
+        .option(Parameter::KeyValue("filter_complex", &filter))
+        .output(
+            File::new("-")
+                .option(Parameter::KeyValue("f", "matroska"))
+                .option(Parameter::KeyValue("map", map.as_str()))
+                .option(Parameter::KeyValue("c:v", "libx264"))
+                .option(Parameter::KeyValue("preset", "ultrafast"))
+                .option(Parameter::KeyValue("crf", "12"))
+                .option(Parameter::KeyValue("t", duration_str.as_str())),
+        )
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .to_command();
+    debug!("Making a slideshow of {} pictures: {:?}", images.len(), cmd);
+
+    let (bytes, _) = run_ffmpeg(cmd, None).await?;
+    Ok(Media {
+        bytes,
+        mimetype: "video/x-matroska".to_string(),
+        filename: "slideshow.mkv".to_string(),
+        url: None,
+    })
+}
+
+/// The filter that fits `count` pictures to the same size and cross-fades
+/// between them. Returns the filter and the label of its output.
+fn slideshow_filter(count: usize, shown: f32, fade: f32) -> (String, String) {
+    let mut filters: Vec<String> = (0..count)
+        .map(|i| {
+            format!(
+                "[{i}:v]scale={WIDTH}:{HEIGHT}:force_original_aspect_ratio=decrease,\
+                 pad={WIDTH}:{HEIGHT}:(ow-iw)/2:(oh-ih)/2,setsar=1,fps={FRAMERATE},\
+                 format=yuv420p[s{i}]"
+            )
+        })
+        .collect();
+
+    // Each fade starts just before the previous picture's time is up.
+    let mut last = "s0".to_string();
+    for i in 1..count {
+        let offset = i as f32 * (shown - fade);
+        filters.push(format!(
+            "[{last}][s{i}]xfade=transition=fade:duration={fade}:offset={offset}[x{i}]"
+        ));
+        last = format!("x{i}");
+    }
+    (filters.join(";"), format!("[{last}]"))
+}
@@ -1,13 +1,10 @@
 use base64::prelude::*;
-use log::{debug, error, info, trace, warn};
+use log::{debug, trace, warn};
 use reqwest::{Client, Response, StatusCode};
 use serde::Deserialize;
 use serde_json::Value;
-use serenity::prelude::{TypeMapKey, RwLock};
-use std::{
-    collections::HashMap,
-    fmt::{self, Display}, sync::Arc,
-};
+use serenity::prelude::{RwLock, TypeMapKey};
+use std::{collections::HashMap, fmt, sync::Arc};
 
 #[derive(Clone)]
 pub struct Spotify {
@@ -102,20 +99,16 @@ async fn error_from(response: Response) -> SpotifyError {
 
     // If the error is a string, print it, else parse it as an ApiError.
     match error {
-        Value::String(msg) => {
-            return SpotifyError::ApiError(ApiError {
-                message: msg.to_string(),
-                status: status.as_u16(),
-            });
-        }
+        Value::String(msg) => SpotifyError::ApiError(ApiError {
+            message: msg.to_string(),
+            status: status.as_u16(),
+        }),
         _ => match serde_json::from_value::<ApiError>(error.to_owned()) {
-            Ok(error) => return SpotifyError::ApiError(error),
-            Err(_) => {
-                return SpotifyError::ApiError(ApiError {
-                    message: error.as_str().expect("No Error String").to_owned(),
-                    status: status.as_u16(),
-                })
-            }
+            Ok(error) => SpotifyError::ApiError(error),
+            Err(_) => SpotifyError::ApiError(ApiError {
+                message: error.as_str().expect("No Error String").to_owned(),
+                status: status.as_u16(),
+            }),
         },
     }
 }
@@ -148,7 +141,6 @@ pub struct ApiError {
 pub enum SpotifyError {
     Generic(StatusCode),
     ApiError(ApiError),
-    Unauthorized,
     InvalidToken,
     NotFound,
     Reqwest(reqwest::Error),
@@ -159,7 +151,6 @@ impl fmt::Display for SpotifyError {
         match self {
             SpotifyError::Generic(e) => write!(f, "Http {}", e),
             SpotifyError::ApiError(e) => write!(f, "Api Error {} {}", e.status, e.message),
-            SpotifyError::Unauthorized => write!(f, "Unauthorized"),
             SpotifyError::InvalidToken => write!(f, "Invalid Token"),
             SpotifyError::NotFound => write!(f, "Not Found or Not Available"),
             SpotifyError::Reqwest(e) => write!(f, "Reqwest: {}", e),